@@ -3,6 +3,14 @@ set -ex
 main() {
     cross build --target $TARGET
     cross build --target $TARGET --release
+    cross build --target $TARGET --features rpc
+
+    # the hardware independent protocol code is tested on the host, with the
+    # PTY stand-in for the board
+    if [ $TARGET = x86_64-unknown-linux-gnu ]; then
+        cargo test --manifest-path protocol/Cargo.toml --target $TARGET
+        cargo test --manifest-path protocol/Cargo.toml --target $TARGET --features std
+    fi
 }
 
 main
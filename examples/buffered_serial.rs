@@ -14,7 +14,7 @@ extern crate cortex_m_rtfm as rtfm;
 extern crate bluepill;
 extern crate heapless;
 
-use bluepill::serial::Serial;
+use bluepill::serial::{BufferedSerial, Config, Serial};
 use bluepill::stm32f103xx::interrupt::Usart3;
 use bluepill::stm32f103xx;
 use rtfm::{Resource, P0, P1, T0, T1, TMax, C1};
@@ -26,11 +26,13 @@ pub const BAUD_RATE: u32 = 115_200; // bits per second
 
 struct Buffer {
     buff: RefCell<Vec<u8, [u8; 20]>>,
+    serial: RefCell<BufferedSerial>,
 }
 
 impl Buffer {
     const fn new() -> Self {
-        Buffer {buff: RefCell::new(Vec::new([0; 20]))}
+        Buffer {buff: RefCell::new(Vec::new([0; 20])),
+                serial: RefCell::new(BufferedSerial::new())}
     }
 }
 
@@ -60,7 +62,7 @@ fn init(ref priority: P0, threshold: &TMax) {
 
     let serial = Serial{usart: &**usart3};
 
-    serial.init(&gpiob, &rcc, BAUD_RATE);
+    serial.init(&gpiob, &rcc, Config::new(BAUD_RATE)).unwrap();
 }
 
 // IDLE LOOP
@@ -80,32 +82,29 @@ tasks!(stm32f103xx, {
     },
 });
 
-// Send back the received byte
+// Send back the received line
 fn loopback(mut _task: Usart3, ref priority: P1, ref threshold: T1) {
     let rxq = RXQ.access(priority, threshold);
     let mut buff = rxq.buff.borrow_mut();
+    let mut buffered = rxq.serial.borrow_mut();
 
     let usart3 = USART3.access(priority, threshold);
     let serial = Serial{usart: &**usart3};
 
-    if let Some(byte) = serial.read() {
+    // move received bytes into the ring buffer and keep transmitting
+    buffered.on_interrupt(serial);
+
+    while let Some(byte) = buffered.read(serial) {
         if buff.push(byte).is_err() {
-                // error: buffer full
-                // KISS: we just clear the buffer when it gets full
-                buff.clear();
-            }
+            // error: buffer full
+            // KISS: we just clear the buffer when it gets full
+            buff.clear();
+        }
         // Carriage return
         if byte == 13 {
-            while let Some(x) = buff.pop(){
-                while serial.write(x).is_err() {
-                    // resend if tx buffer is full
-                    // should put a timeout in here later
-                }
-            }
+            // queued and sent from the TXE interrupt, never blocks
+            buffered.write_all(serial, &buff);
+            buff.clear();
         }
-    } else {
-        // Only reachable through `rtfm::request(loopback)`
-        #[cfg(debug_assertions)]
-        unreachable!()
     }
 }
@@ -13,7 +13,7 @@ extern crate cortex_m_rtfm as rtfm;
 
 extern crate bluepill;
 
-use bluepill::serial::Serial;
+use bluepill::serial::{Config, Error, Serial};
 use bluepill::stm32f103xx::interrupt::Usart3;
 use bluepill::stm32f103xx;
 use rtfm::{P0, P1, T0, T1, TMax};
@@ -45,7 +45,7 @@ fn init(ref priority: P0, threshold: &TMax) {
 
     let serial = Serial{usart: &usart3};
 
-    serial.init(&gpiob, &rcc, BAUD_RATE);
+    serial.init(&gpiob, &rcc, Config::new(BAUD_RATE)).unwrap();
 }
 
 // IDLE LOOP
@@ -70,16 +70,23 @@ fn loopback(_task: Usart3, ref priority: P1, ref threshold: T1) {
     let usart3 = USART3.access(priority, threshold);
     let serial = Serial{usart: &usart3};
 
-    if let Some(byte) = serial.read() {
-        if serial.write(byte).is_err() {
-            // As we are echoing the bytes as soon as they arrive, it should
-            // be impossible to have a TX buffer overrun
+    match serial.read() {
+        Ok(byte) => {
+            if serial.write(byte).is_err() {
+                // As we are echoing the bytes as soon as they arrive, it should
+                // be impossible to have a TX buffer overrun
+                #[cfg(debug_assertions)]
+                unreachable!()
+            }
+        }
+        Err(Error::WouldBlock) => {
+            // Only reachable through `rtfm::request(loopback)`
             #[cfg(debug_assertions)]
             unreachable!()
         }
-    } else {
-        // Only reachable through `rtfm::request(loopback)`
-        #[cfg(debug_assertions)]
-        unreachable!()
+        Err(_) => {
+            // Receive error, the byte is dropped and the error flags have
+            // been cleared by `read`
+        }
     }
 }
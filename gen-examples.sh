@@ -13,6 +13,7 @@ main() {
         pwm
         stepper_tasks
         stepper
+        shell
     )
 
     rm -rf src/examples
@@ -37,7 +38,7 @@ EOF
         grep -v '//!' examples/$ex.rs | (
             IFS=''
 
-            while read line; do
+            while read -r line; do
                 echo "//! $line" >> $out;
             done
         )
@@ -0,0 +1,24 @@
+//! Serves the RPC protocol on a pseudo terminal, against a simulated board
+//!
+//! Point a host script at the printed device instead of the bluepill's
+//! serial port.
+//!
+//! ``` text
+//! cargo run --manifest-path protocol/Cargo.toml --features std --example pty
+//! ```
+
+extern crate bluepill_protocol;
+
+use bluepill_protocol::host::{Pty, Simulator};
+use bluepill_protocol::rpc::{Server, MAX_PAYLOAD};
+
+fn main() {
+    let mut pty = Pty::open().expect("can't open a pseudo terminal");
+    let mut board = Simulator::new();
+    let mut server = Server::new([0; MAX_PAYLOAD + 2]);
+
+    println!("serving on {}", pty.name());
+    loop {
+        pty.poll(&mut server, &mut board).expect("can't read the pseudo terminal");
+    }
+}
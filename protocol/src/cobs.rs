@@ -0,0 +1,268 @@
+//! Consistent Overhead Byte Stuffing (COBS) framing
+//!
+//! COBS removes every zero from a packet, so a single zero byte can mark the
+//! end of each packet on the wire. The packet is split into blocks of up to
+//! 254 bytes, each prefixed with a code byte giving the distance to the next
+//! zero. The overhead is one byte per 254 bytes of data, no matter what the
+//! data is, which makes it the better fit for binary protocols.
+
+use packet::Packet;
+
+/// Longest run of data bytes in one COBS block
+const MAX_RUN: usize = 254;
+
+/// Specialized `Result` type
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// An error
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// the packet ended in the middle of a block
+    Framing,
+    /// the packet doesn't fit in the decoder buffer
+    Overflow,
+}
+
+#[derive(Clone, Copy)]
+enum EncoderState {
+    Code,
+    Data,
+    Delimiter,
+    Done,
+}
+
+/// Encodes a packet, yielding the encoded bytes and the terminating zero
+pub struct Encoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    run: usize,
+    zero: bool,
+    state: EncoderState,
+}
+
+impl<'a> Encoder<'a> {
+    /// returns an encoder for `data`
+    pub fn new(data: &'a [u8]) -> Encoder<'a> {
+        Encoder { data, pos: 0, run: 0, zero: false, state: EncoderState::Code }
+    }
+
+    fn end_block(&mut self) {
+        self.state = if self.zero {
+            // skip the zero, it is implied by the code byte
+            self.pos += 1;
+            EncoderState::Code
+        } else if self.pos < self.data.len() {
+            EncoderState::Code
+        } else {
+            EncoderState::Delimiter
+        };
+    }
+}
+
+impl<'a> Iterator for Encoder<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        match self.state {
+            EncoderState::Code => {
+                let rest = &self.data[self.pos..];
+                let run = rest.iter()
+                    .take(MAX_RUN)
+                    .position(|&byte| byte == 0)
+                    .unwrap_or(if rest.len() < MAX_RUN { rest.len() } else { MAX_RUN });
+
+                // a full block has no implied zero
+                self.zero = run < MAX_RUN && run < rest.len();
+                self.run = run;
+                if run == 0 {
+                    self.end_block();
+                } else {
+                    self.state = EncoderState::Data;
+                }
+                Some(run as u8 + 1)
+            },
+            EncoderState::Data => {
+                let byte = self.data[self.pos];
+                self.pos += 1;
+                self.run -= 1;
+                if self.run == 0 {
+                    self.end_block();
+                }
+                Some(byte)
+            },
+            EncoderState::Delimiter => {
+                self.state = EncoderState::Done;
+                Some(0)
+            },
+            EncoderState::Done => None,
+        }
+    }
+}
+
+/// Incremental decoder
+///
+/// Undoes the byte stuffing as the bytes arrive, so only the decoded packet
+/// is buffered
+pub struct Decoder<A> {
+    packet: Packet<A>,
+    // code byte of the current block
+    code: u8,
+    // data bytes left in the current block
+    remaining: u8,
+}
+
+impl<A> Decoder<A> {
+    /// returns a decoder writing into `buffer`, which must hold the largest
+    /// decoded packet
+    pub const fn new(buffer: A) -> Decoder<A> {
+        Decoder { packet: Packet::new(buffer), code: 0, remaining: 0 }
+    }
+}
+
+impl<A> Decoder<A>
+where
+    A: AsMut<[u8]> + AsRef<[u8]>,
+{
+    /// Feeds a received byte
+    ///
+    /// Returns the decoded packet at the zero delimiter. A packet that ends
+    /// inside a block is a `Framing` error, the decoder resynchronizes on
+    /// the next zero, so receiving can start in the middle of a stream.
+    pub fn feed(&mut self, byte: u8) -> Result<Option<&[u8]>> {
+        if byte == 0 {
+            let code = self.code;
+            let complete = self.remaining == 0;
+            self.code = 0;
+            self.remaining = 0;
+
+            return match self.packet.finish() {
+                // back to back delimiters, or an error that was already reported
+                None => Ok(None),
+                Some(_) if code == 0 => Ok(None),
+                Some(packet) => if complete {
+                    Ok(Some(packet))
+                } else {
+                    Err(Error::Framing)
+                },
+            };
+        }
+
+        if self.packet.is_discarding() {
+            return Ok(None);
+        }
+
+        let pushed = if self.remaining == 0 {
+            // every block but a full one ends with an implied zero
+            let zero = self.code != 0 && (self.code as usize) <= MAX_RUN;
+            self.code = byte;
+            self.remaining = byte - 1;
+            !zero || self.packet.push(0)
+        } else {
+            self.remaining -= 1;
+            self.packet.push(byte)
+        };
+
+        if pushed {
+            Ok(None)
+        } else {
+            Err(Error::Overflow)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    fn encode(data: &[u8]) -> Vec<u8> {
+        Encoder::new(data).collect()
+    }
+
+    fn decode(encoded: &[u8]) -> Vec<u8> {
+        let mut decoder = Decoder::new([0; 512]);
+        let mut packet = None;
+        for &byte in encoded {
+            if let Some(decoded) = decoder.feed(byte).unwrap() {
+                assert!(packet.is_none());
+                packet = Some(decoded.to_vec());
+            }
+        }
+        packet.unwrap()
+    }
+
+    fn check(data: &[u8], encoded: &[u8]) {
+        assert_eq!(encode(data), encoded);
+        assert_eq!(decode(encoded), data);
+    }
+
+    fn with(start: &[u8], range: ::core::ops::Range<u16>, end: &[u8]) -> Vec<u8> {
+        let mut v = start.to_vec();
+        v.extend(range.map(|b| b as u8));
+        v.extend_from_slice(end);
+        v
+    }
+
+    // examples from the Wikipedia article on COBS
+
+    #[test]
+    fn short_packets() {
+        check(&[0x00], &[0x01, 0x01, 0x00]);
+        check(&[0x00, 0x00], &[0x01, 0x01, 0x01, 0x00]);
+        check(&[0x00, 0x11, 0x00], &[0x01, 0x02, 0x11, 0x01, 0x00]);
+        check(&[0x11, 0x22, 0x00, 0x33], &[0x03, 0x11, 0x22, 0x02, 0x33, 0x00]);
+        check(&[0x11, 0x22, 0x33, 0x44], &[0x05, 0x11, 0x22, 0x33, 0x44, 0x00]);
+        check(&[0x11, 0x00, 0x00, 0x00], &[0x02, 0x11, 0x01, 0x01, 0x01, 0x00]);
+    }
+
+    #[test]
+    fn full_blocks() {
+        check(&with(&[], 0x01..0xFF, &[]), &with(&[0xFF], 0x01..0xFF, &[0x00]));
+        check(&with(&[], 0x00..0xFF, &[]), &with(&[0x01, 0xFF], 0x01..0xFF, &[0x00]));
+        check(&with(&[], 0x01..0x100, &[]), &with(&[0xFF], 0x01..0xFF, &[0x02, 0xFF, 0x00]));
+        check(&with(&[], 0x02..0x100, &[0x00]),
+              &with(&[0xFF], 0x02..0x100, &[0x01, 0x01, 0x00]));
+        check(&with(&[], 0x03..0x100, &[0x00, 0x01]),
+              &with(&[0xFE], 0x03..0x100, &[0x02, 0x01, 0x00]));
+    }
+
+    #[test]
+    fn empty_packet() {
+        assert_eq!(encode(&[]), [0x01, 0x00]);
+        let mut decoder = Decoder::new([0; 4]);
+        assert_eq!(decoder.feed(0x01), Ok(None));
+        assert_eq!(decoder.feed(0x00), Ok(Some(&[][..])));
+    }
+
+    #[test]
+    fn resynchronizes() {
+        let mut decoder = Decoder::new([0; 4]);
+        // back to back delimiters are skipped
+        assert_eq!(decoder.feed(0x00), Ok(None));
+        // a block cut short by a delimiter
+        assert_eq!(decoder.feed(0x03), Ok(None));
+        assert_eq!(decoder.feed(0x11), Ok(None));
+        assert_eq!(decoder.feed(0x00), Err(Error::Framing));
+        // the next packet decodes normally
+        for &byte in &[0x02, 0x11, 0x01] {
+            assert_eq!(decoder.feed(byte), Ok(None));
+        }
+        assert_eq!(decoder.feed(0x00), Ok(Some(&[0x11, 0x00][..])));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut decoder = Decoder::new([0; 2]);
+        assert_eq!(decoder.feed(0x04), Ok(None));
+        assert_eq!(decoder.feed(0x11), Ok(None));
+        assert_eq!(decoder.feed(0x22), Ok(None));
+        assert_eq!(decoder.feed(0x33), Err(Error::Overflow));
+        // reported once, the rest of the packet is dropped
+        assert_eq!(decoder.feed(0x44), Ok(None));
+        assert_eq!(decoder.feed(0x00), Ok(None));
+        assert_eq!(decoder.feed(0x02), Ok(None));
+        assert_eq!(decoder.feed(0x55), Ok(None));
+        assert_eq!(decoder.feed(0x00), Ok(Some(&[0x55][..])));
+    }
+}
@@ -0,0 +1,261 @@
+//! Linux stand-in for the board
+//!
+//! Runs the `rpc` server on a pseudo terminal against a simulated board, so
+//! host scripts can be tested without hardware. The script opens the slave
+//! side, `Pty::name`, like any serial port. Enabled with the `std` feature.
+//!
+//! ``` no_run
+//! use bluepill_protocol::host::{Pty, Simulator};
+//! use bluepill_protocol::rpc::{Server, MAX_PAYLOAD};
+//!
+//! let mut pty = Pty::open().unwrap();
+//! let mut board = Simulator::new();
+//! let mut server = Server::new([0; MAX_PAYLOAD + 2]);
+//! println!("serving on {}", pty.name());
+//! loop {
+//!     pty.poll(&mut server, &mut board).unwrap();
+//! }
+//! ```
+
+use std::ffi::CStr;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::string::String;
+
+use libc;
+
+use rpc::{self, Board, Server, Transport};
+
+/// Number of pins of the simulated board
+pub const PINS: usize = 16;
+/// Number of timers of the simulated board
+pub const TIMERS: usize = 4;
+
+/// Pseudo terminal standing in for the serial port
+pub struct Pty {
+    master: File,
+    // keeps the slave side open, otherwise reading the master fails with EIO
+    // while no host script has it open
+    _slave: File,
+    name: String,
+}
+
+impl Pty {
+    /// Opens a new pseudo terminal in raw mode
+    pub fn open() -> io::Result<Pty> {
+        // NOTE(safe) the descriptor is owned by `master` right away
+        let master = unsafe {
+            let fd = libc::posix_openpt(libc::O_RDWR | libc::O_NOCTTY);
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            File::from_raw_fd(fd)
+        };
+        let fd = master.as_raw_fd();
+
+        let mut name = [0; 64];
+        // NOTE(safe) `ptsname_r` writes a nul terminated path into `name`
+        let name = unsafe {
+            if libc::grantpt(fd) != 0 || libc::unlockpt(fd) != 0 ||
+                libc::ptsname_r(fd, name.as_mut_ptr(), name.len()) != 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            CStr::from_ptr(name.as_ptr()).to_string_lossy().into_owned()
+        };
+
+        let slave = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(&name)?;
+
+        // no echo, no line editing and no CR/LF translation, bytes go through
+        // untouched in both directions
+        // NOTE(safe) `termios` is plain data, filled in by `tcgetattr`
+        unsafe {
+            let mut termios = mem::zeroed();
+            if libc::tcgetattr(slave.as_raw_fd(), &mut termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+            libc::cfmakeraw(&mut termios);
+            if libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &termios) != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(Pty { master, _slave: slave, name })
+    }
+
+    /// Returns the path of the slave side, for example `/dev/pts/3`
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waits for bytes from the host and feeds them to `server`, responses
+    /// are written back to the host
+    pub fn poll<A, B>(&mut self, server: &mut Server<A>, board: &mut B) -> io::Result<()>
+    where
+        A: AsMut<[u8]> + AsRef<[u8]>,
+        B: Board,
+    {
+        let mut buffer = [0; 64];
+        let len = self.master.read(&mut buffer)?;
+        for &byte in &buffer[..len] {
+            server.feed(byte, board, self);
+        }
+        self.master.flush()
+    }
+}
+
+impl Transport for Pty {
+    fn send(&mut self, byte: u8) {
+        // a host script that went away just misses the response
+        self.master.write_all(&[byte]).ok();
+    }
+}
+
+/// Board simulated in memory
+///
+/// Every field is public so tests can set inputs and check outputs. Nothing
+/// changes on its own, counters only move when a test sets them.
+pub struct Simulator {
+    /// pin levels, set by `digital_write` and returned by `digital_read`
+    pub levels: [bool; PINS],
+    /// last duty cycle written to each pin
+    pub duty_cycles: [u8; PINS],
+    /// values returned by `analog_read`
+    pub analog: [u16; PINS],
+    /// timer counters
+    pub counts: [u16; TIMERS],
+    /// compare registers of each timer, channel 1 first
+    pub compares: [[u16; 4]; TIMERS],
+    /// `true` if the timer is running
+    pub running: [bool; TIMERS],
+}
+
+impl Simulator {
+    /// returns a board with every pin low and every timer stopped at zero
+    pub fn new() -> Simulator {
+        Simulator {
+            levels: [false; PINS],
+            duty_cycles: [0; PINS],
+            analog: [0; PINS],
+            counts: [0; TIMERS],
+            compares: [[0; 4]; TIMERS],
+            running: [false; TIMERS],
+        }
+    }
+}
+
+impl Default for Simulator {
+    fn default() -> Simulator {
+        Simulator::new()
+    }
+}
+
+fn index(index: u8, len: usize) -> rpc::Result<usize> {
+    if (index as usize) < len {
+        Ok(index as usize)
+    } else {
+        Err(rpc::Error::Index)
+    }
+}
+
+impl Board for Simulator {
+    fn digital_write(&mut self, pin: u8, high: bool) -> rpc::Result<()> {
+        self.levels[index(pin, PINS)?] = high;
+        Ok(())
+    }
+
+    fn digital_read(&mut self, pin: u8) -> rpc::Result<bool> {
+        Ok(self.levels[index(pin, PINS)?])
+    }
+
+    fn pwm_write(&mut self, pin: u8, duty_cycle: u8) -> rpc::Result<()> {
+        self.duty_cycles[index(pin, PINS)?] = duty_cycle;
+        Ok(())
+    }
+
+    fn analog_read(&mut self, pin: u8) -> rpc::Result<u16> {
+        Ok(self.analog[index(pin, PINS)?])
+    }
+
+    fn timer_count(&mut self, timer: u8) -> rpc::Result<u16> {
+        Ok(self.counts[index(timer, TIMERS)?])
+    }
+
+    fn timer_compare(&mut self, timer: u8, channel: u8, value: u16) -> rpc::Result<()> {
+        self.compares[index(timer, TIMERS)?][channel as usize - 1] = value;
+        Ok(())
+    }
+
+    fn timer_run(&mut self, timer: u8, running: bool) -> rpc::Result<()> {
+        self.running[index(timer, TIMERS)?] = running;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use rpc::*;
+
+    use super::*;
+
+    // sends a request from the host side and reads back the response frame
+    fn call(pty: &mut Pty, host: &mut File, board: &mut Simulator, payload: &[u8]) -> Vec<u8> {
+        let mut server = Server::new([0; MAX_PAYLOAD + 2]);
+        host.write_all(&frame(payload)).unwrap();
+        host.flush().unwrap();
+
+        let mut response = Vec::new();
+        while response.last() != Some(&0) {
+            if response.is_empty() {
+                pty.poll(&mut server, board).unwrap();
+            }
+            let mut byte = [0];
+            host.read_exact(&mut byte).unwrap();
+            response.push(byte[0]);
+        }
+        response
+    }
+
+    fn host(pty: &Pty) -> File {
+        OpenOptions::new()
+            .read(true)
+            .write(true)
+            .custom_flags(libc::O_NOCTTY)
+            .open(pty.name())
+            .unwrap()
+    }
+
+    #[test]
+    fn requests_through_pty() {
+        let mut pty = Pty::open().unwrap();
+        let mut host = host(&pty);
+        let mut board = Simulator::new();
+
+        // zeros and CR/LF bytes have to survive the line discipline
+        let ping = [1, PING, 0x00, b'\r', b'\n', 0x03, 0x7F];
+        assert_eq!(call(&mut pty, &mut host, &mut board, &ping), frame(&[1, 0, 0x00, b'\r', b'\n', 0x03, 0x7F]));
+
+        assert_eq!(call(&mut pty, &mut host, &mut board, &[2, DIGITAL_WRITE, 13, 1]), frame(&[2, 0]));
+        assert!(board.levels[13]);
+
+        board.analog[3] = 0x0ABC;
+        assert_eq!(call(&mut pty, &mut host, &mut board, &[3, ANALOG_READ, 3]), frame(&[3, 0, 0xBC, 0x0A]));
+
+        assert_eq!(call(&mut pty, &mut host, &mut board, &[4, TIMER_COMPARE, 1, 4, 0x34, 0x12]), frame(&[4, 0]));
+        assert_eq!(board.compares[1], [0, 0, 0, 0x1234]);
+
+        assert_eq!(
+            call(&mut pty, &mut host, &mut board, &[5, TIMER_RUN, TIMERS as u8, 1]),
+            frame(&[5, Error::Index as u8])
+        );
+    }
+}
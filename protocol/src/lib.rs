@@ -0,0 +1,32 @@
+//! Hardware independent protocol code of the bluepill crate
+//!
+//! Everything here only deals with bytes, it doesn't touch any peripheral.
+//! The `bluepill` crate re-exports these modules and drives them from its
+//! serial drivers. Keeping them out of the board crate lets the test suite
+//! run on the host:
+//!
+//! ``` text
+//! cargo test --manifest-path protocol/Cargo.toml --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! The `std` feature adds the `host` module, a PTY stand-in for the board
+//! that runs the `rpc` server on Linux. `examples/pty.rs` serves it to host
+//! scripts.
+
+#![deny(missing_docs)]
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate libc;
+#[cfg(any(test, feature = "std"))]
+extern crate std;
+
+pub mod cobs;
+#[cfg(feature = "std")]
+pub mod host;
+pub mod lin;
+pub mod rpc;
+pub mod shell;
+pub mod slip;
+
+mod packet;
@@ -0,0 +1,256 @@
+//! LIN bus frame layer
+//!
+//! A frame is a break, the 0x55 sync byte, a protected identifier and up to 8
+//! data bytes followed by a checksum. `Parser` follows the bus byte by byte,
+//! the caller reports breaks, since a UART only sees them as a line
+//! condition.
+
+/// Sync byte following the break
+pub const SYNC: u8 = 0x55;
+
+/// Maximum number of data bytes in a frame
+pub const MAX_DATA: usize = 8;
+
+/// Specialized `Result` type
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// An error
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// the byte after the break wasn't 0x55
+    Sync,
+    /// the parity bits of the protected identifier don't match
+    Parity,
+    /// the frame checksum doesn't match
+    Checksum,
+    /// more than 8 data bytes requested
+    Length,
+}
+
+/// Checksum model
+#[derive(Clone, Copy, PartialEq)]
+pub enum Checksum {
+    /// LIN 1.x, data bytes only
+    Classic,
+    /// LIN 2.x, protected identifier and data bytes
+    Enhanced,
+}
+
+/// A complete frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    /// frame identifier, 0 to 63
+    pub id: u8,
+    /// data bytes
+    pub data: [u8; MAX_DATA],
+    /// number of valid data bytes
+    pub len: usize,
+}
+
+/// What the parser saw
+#[derive(Debug, PartialEq)]
+pub enum Received {
+    /// a header with this identifier, call `Parser::expect` to receive the
+    /// response or send one
+    Header(u8),
+    /// a complete response
+    Frame(Frame),
+}
+
+/// Returns the protected identifier of `id`, with parity bits P0 and P1
+pub fn protected_id(id: u8) -> u8 {
+    let id = id & 0x3F;
+    let bit = |n: u8| (id >> n) & 1;
+    let p0 = bit(0) ^ bit(1) ^ bit(2) ^ bit(4);
+    let p1 = !(bit(1) ^ bit(3) ^ bit(4) ^ bit(5)) & 1;
+    id | (p0 << 6) | (p1 << 7)
+}
+
+/// Returns the checksum of a frame
+pub fn checksum(model: Checksum, pid: u8, data: &[u8]) -> u8 {
+    // the diagnostic frames 0x3C and 0x3D always use the classic checksum
+    let mut sum: u16 = if model == Checksum::Enhanced && pid & 0x3F < 0x3C {
+        pid as u16
+    } else {
+        0
+    };
+
+    // sum with carry
+    for &byte in data {
+        sum += byte as u16;
+        if sum > 0xFF {
+            sum -= 0xFF;
+        }
+    }
+
+    !(sum as u8)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    Idle,
+    Sync,
+    Pid,
+    Header,
+    Data,
+}
+
+/// Incremental frame parser
+pub struct Parser {
+    checksum: Checksum,
+    state: State,
+    pid: u8,
+    data: [u8; MAX_DATA],
+    count: usize,
+    len: usize,
+}
+
+impl Parser {
+    /// returns a parser for a cluster using the `checksum` model
+    pub const fn new(checksum: Checksum) -> Parser {
+        Parser {
+            checksum,
+            state: State::Idle,
+            pid: 0,
+            data: [0; MAX_DATA],
+            count: 0,
+            len: 0,
+        }
+    }
+
+    /// Starts a new frame, call this when a break is detected
+    pub fn on_break(&mut self) {
+        self.state = State::Sync;
+        self.count = 0;
+        self.len = 0;
+    }
+
+    /// Receives `len` data bytes after a `Header`
+    ///
+    /// Returns `Err` if `len` is larger than 8
+    pub fn expect(&mut self, len: usize) -> Result<()> {
+        if len > MAX_DATA {
+            return Err(Error::Length);
+        }
+        if self.state == State::Header {
+            self.state = State::Data;
+            self.len = len;
+            self.count = 0;
+        }
+        Ok(())
+    }
+
+    /// Feeds a received byte
+    ///
+    /// Bytes outside of a frame are ignored. A malformed frame is reported
+    /// once and the parser waits for the next break.
+    pub fn feed(&mut self, byte: u8) -> Result<Option<Received>> {
+        match self.state {
+            State::Idle | State::Header => Ok(None),
+            State::Sync => {
+                if byte == SYNC {
+                    self.state = State::Pid;
+                    Ok(None)
+                } else {
+                    self.state = State::Idle;
+                    Err(Error::Sync)
+                }
+            },
+            State::Pid => {
+                if protected_id(byte) == byte {
+                    self.pid = byte;
+                    self.state = State::Header;
+                    Ok(Some(Received::Header(byte & 0x3F)))
+                } else {
+                    self.state = State::Idle;
+                    Err(Error::Parity)
+                }
+            },
+            State::Data => {
+                if self.count < self.len {
+                    self.data[self.count] = byte;
+                    self.count += 1;
+                    return Ok(None);
+                }
+
+                self.state = State::Idle;
+                if checksum(self.checksum, self.pid, &self.data[..self.len]) == byte {
+                    Ok(Some(Received::Frame(Frame {
+                        id: self.pid & 0x3F,
+                        data: self.data,
+                        len: self.len,
+                    })))
+                } else {
+                    Err(Error::Checksum)
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protected_ids() {
+        assert_eq!(protected_id(0x00), 0x80);
+        assert_eq!(protected_id(0x01), 0xC1);
+        assert_eq!(protected_id(0x02), 0x42);
+        assert_eq!(protected_id(0x03), 0x03);
+        assert_eq!(protected_id(0x3C), 0x3C);
+        assert_eq!(protected_id(0x3D), 0x7D);
+        assert_eq!(protected_id(0x3E), 0xFE);
+        assert_eq!(protected_id(0x3F), 0xBF);
+    }
+
+    #[test]
+    fn checksums() {
+        // example from the LIN 2.x specification, section 2.8.3
+        assert_eq!(checksum(Checksum::Enhanced, 0x4A, &[0x55, 0x93, 0xE5]), 0xE6);
+        assert_eq!(checksum(Checksum::Classic, 0x4A, &[0x55, 0x93, 0xE5]), 0x31);
+        // diagnostic frames always use the classic checksum
+        assert_eq!(checksum(Checksum::Enhanced, 0x3C, &[0x55, 0x93, 0xE5]), 0x31);
+    }
+
+    #[test]
+    fn parse_frame() {
+        let mut parser = Parser::new(Checksum::Enhanced);
+        // bytes before the first break are ignored
+        assert_eq!(parser.feed(0x12), Ok(None));
+
+        parser.on_break();
+        assert_eq!(parser.feed(SYNC), Ok(None));
+        assert_eq!(parser.feed(0xCA), Ok(Some(Received::Header(0x0A))));
+
+        let data = [0x55, 0x93, 0xE5];
+        parser.expect(data.len()).unwrap();
+        for &byte in &data {
+            assert_eq!(parser.feed(byte), Ok(None));
+        }
+        let mut frame = Frame { id: 0x0A, data: [0; MAX_DATA], len: data.len() };
+        frame.data[..data.len()].copy_from_slice(&data);
+        assert_eq!(parser.feed(checksum(Checksum::Enhanced, 0xCA, &data)),
+                   Ok(Some(Received::Frame(frame))));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let mut parser = Parser::new(Checksum::Enhanced);
+        parser.on_break();
+        assert_eq!(parser.feed(0x00), Err(Error::Sync));
+
+        parser.on_break();
+        assert_eq!(parser.feed(SYNC), Ok(None));
+        assert_eq!(parser.feed(0x0A), Err(Error::Parity));
+
+        parser.on_break();
+        parser.feed(SYNC).unwrap();
+        parser.feed(0xCA).unwrap();
+        parser.expect(1).unwrap();
+        parser.feed(0x55).unwrap();
+        assert_eq!(parser.feed(0x00), Err(Error::Checksum));
+
+        assert_eq!(parser.expect(9), Err(Error::Length));
+    }
+}
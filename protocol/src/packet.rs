@@ -0,0 +1,62 @@
+//! Packet buffer shared by the decoders
+
+/// Collects the bytes of one packet in `buffer`
+///
+/// After an overflow or a framing error the decoder calls `discard`, the
+/// rest of the packet is then dropped up to its delimiter.
+pub(crate) struct Packet<A> {
+    buffer: A,
+    len: usize,
+    discard: bool,
+}
+
+impl<A> Packet<A> {
+    pub(crate) const fn new(buffer: A) -> Packet<A> {
+        Packet { buffer, len: 0, discard: false }
+    }
+
+    /// Drops the rest of the current packet
+    pub(crate) fn discard(&mut self) {
+        self.discard = true;
+    }
+
+    pub(crate) fn is_discarding(&self) -> bool {
+        self.discard
+    }
+}
+
+impl<A> Packet<A>
+where
+    A: AsMut<[u8]> + AsRef<[u8]>,
+{
+    /// Appends a byte
+    ///
+    /// Returns `false` if the buffer is full, the packet is discarded then
+    pub(crate) fn push(&mut self, byte: u8) -> bool {
+        match self.buffer.as_mut().get_mut(self.len) {
+            Some(slot) => *slot = byte,
+            None => {
+                self.discard = true;
+                return false;
+            },
+        }
+        self.len += 1;
+        true
+    }
+
+    /// Ends the current packet and starts the next one
+    ///
+    /// Returns the collected bytes, or `None` if the packet was discarded
+    pub(crate) fn finish(&mut self) -> Option<&[u8]> {
+        let len = self.len;
+        let discard = self.discard;
+        self.len = 0;
+        self.discard = false;
+
+        if discard {
+            None
+        } else {
+            Some(&self.buffer.as_ref()[..len])
+        }
+    }
+}
@@ -0,0 +1,447 @@
+//! Binary RPC for host controlled testing
+//!
+//! Lets a host script toggle pins, set PWM duty cycles, read the ADC and
+//! drive timers over a serial port without flashing new firmware.
+//!
+//! Every request and response is a COBS frame (see the `cobs` module)
+//! holding a payload followed by its CRC-16/CCITT-FALSE, least significant
+//! byte first.
+//!
+//! - request payload: sequence number, opcode, arguments
+//! - response payload: the request's sequence number, status, data
+//!
+//! Status 0 is success, any other value is an `Error`. Multi-byte values
+//! are little endian. Pins and timers are addressed by index, the `Board`
+//! decides what each index refers to.
+//!
+//! | opcode          | arguments                      | data     |
+//! |-----------------|--------------------------------|----------|
+//! | `PING`          | any                            | the args |
+//! | `DIGITAL_WRITE` | pin, level (0 or 1)            |          |
+//! | `DIGITAL_READ`  | pin                            | level    |
+//! | `PWM_WRITE`     | pin, duty cycle (0 to 255)     |          |
+//! | `ANALOG_READ`   | pin                            | u16      |
+//! | `TIMER_COUNT`   | timer                          | u16      |
+//! | `TIMER_COMPARE` | timer, channel (1 to 4), u16   |          |
+//! | `TIMER_RUN`     | timer, running (0 or 1)        |          |
+//!
+//! The server only talks to the `Board` and `Transport` traits. The
+//! `bluepill` crate implements them on the real peripherals, the `host`
+//! module (`std` feature) on a PTY and a simulated board.
+
+use cobs;
+
+/// Longest request or response payload, without the CRC
+pub const MAX_PAYLOAD: usize = 32;
+/// Longest response on the wire: the payload, the CRC, the COBS code byte
+/// and the delimiter
+pub const MAX_FRAME: usize = MAX_PAYLOAD + 4;
+
+/// Echoes the arguments
+pub const PING: u8 = 0x00;
+/// Drives a pin low or high
+pub const DIGITAL_WRITE: u8 = 0x10;
+/// Reads the level of a pin
+pub const DIGITAL_READ: u8 = 0x11;
+/// Sets the PWM duty cycle of a pin
+pub const PWM_WRITE: u8 = 0x20;
+/// Reads the ADC value of a pin
+pub const ANALOG_READ: u8 = 0x30;
+/// Reads the counter of a timer
+pub const TIMER_COUNT: u8 = 0x40;
+/// Sets a compare register of a timer
+pub const TIMER_COMPARE: u8 = 0x41;
+/// Pauses or resumes a timer
+pub const TIMER_RUN: u8 = 0x42;
+
+/// Specialized `Result` type
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// An error, the discriminant is the status byte sent to the host
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Error {
+    /// the request CRC doesn't match
+    Checksum = 1,
+    /// unknown opcode, or one the board or pin doesn't support
+    Opcode = 2,
+    /// wrong number of argument bytes
+    Length = 3,
+    /// no pin or timer with this index
+    Index = 4,
+    /// an argument is out of range
+    Argument = 5,
+}
+
+/// Board operations exposed to the host
+pub trait Board {
+    /// Drives `pin` high or low
+    fn digital_write(&mut self, pin: u8, high: bool) -> Result<()>;
+    /// Returns `true` if `pin` is high
+    fn digital_read(&mut self, pin: u8) -> Result<bool>;
+    /// Sets the PWM duty cycle of `pin`
+    fn pwm_write(&mut self, pin: u8, duty_cycle: u8) -> Result<()>;
+    /// Returns the ADC value of `pin`
+    fn analog_read(&mut self, pin: u8) -> Result<u16>;
+    /// Returns the counter of `timer`
+    fn timer_count(&mut self, timer: u8) -> Result<u16>;
+    /// Sets the compare register of `channel`, already checked to be 1 to 4
+    fn timer_compare(&mut self, timer: u8, channel: u8, value: u16) -> Result<()>;
+    /// Pauses or resumes `timer`
+    fn timer_run(&mut self, timer: u8, running: bool) -> Result<()>;
+}
+
+/// Byte sink the responses are sent to
+pub trait Transport {
+    /// Sends a byte
+    fn send(&mut self, byte: u8);
+}
+
+/// Returns the CRC-16/CCITT-FALSE of `data`
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0xFFFF;
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ 0x1021
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
+}
+
+/// RPC server decoding requests into `buffer`
+pub struct Server<A> {
+    decoder: cobs::Decoder<A>,
+}
+
+impl<A> Server<A> {
+    /// returns a server decoding requests into `buffer`
+    ///
+    /// `buffer` needs room for `MAX_PAYLOAD` plus the two CRC bytes
+    pub const fn new(buffer: A) -> Server<A> {
+        Server { decoder: cobs::Decoder::new(buffer) }
+    }
+}
+
+impl<A> Server<A>
+where
+    A: AsMut<[u8]> + AsRef<[u8]>,
+{
+    /// Feeds a received byte, running the request on `board` and sending the
+    /// response to `transport` once a frame is complete
+    ///
+    /// Frames that can't be decoded are dropped, the host times out and
+    /// retries
+    pub fn feed<B, T>(&mut self, byte: u8, board: &mut B, transport: &mut T)
+    where
+        B: Board,
+        T: Transport,
+    {
+        let mut response = [0; MAX_PAYLOAD + 2];
+        let len = match self.decoder.feed(byte) {
+            Ok(Some(request)) => match handle(request, board, &mut response[..MAX_PAYLOAD]) {
+                Some(len) => len,
+                None => return,
+            },
+            Ok(None) | Err(_) => return,
+        };
+
+        let crc = crc16(&response[..len]);
+        response[len] = crc as u8;
+        response[len + 1] = (crc >> 8) as u8;
+
+        for byte in cobs::Encoder::new(&response[..len + 2]) {
+            transport.send(byte);
+        }
+    }
+}
+
+/// Checks a request and runs it, returns the length of the response payload
+///
+/// Returns `None` if the request is too short to answer
+fn handle<B: Board>(request: &[u8], board: &mut B, response: &mut [u8]) -> Option<usize> {
+    // sequence number, opcode and CRC
+    if request.len() < 4 {
+        return None;
+    }
+
+    let (payload, crc) = request.split_at(request.len() - 2);
+    response[0] = payload[0];
+
+    let result = if crc16(payload) != crc[0] as u16 | (crc[1] as u16) << 8 {
+        Err(Error::Checksum)
+    } else {
+        execute(payload[1], &payload[2..], board, &mut response[2..])
+    };
+
+    match result {
+        Ok(len) => {
+            response[1] = 0;
+            Some(2 + len)
+        },
+        Err(error) => {
+            response[1] = error as u8;
+            Some(2)
+        },
+    }
+}
+
+/// Runs a request, returns the number of data bytes written to `data`
+fn execute<B: Board>(opcode: u8, args: &[u8], board: &mut B, data: &mut [u8]) -> Result<usize> {
+    match opcode {
+        PING => {
+            if args.len() > data.len() {
+                return Err(Error::Length);
+            }
+            data[..args.len()].copy_from_slice(args);
+            Ok(args.len())
+        },
+        DIGITAL_WRITE => {
+            let args = arguments(args, 2)?;
+            board.digital_write(args[0], flag(args[1])?)?;
+            Ok(0)
+        },
+        DIGITAL_READ => {
+            let args = arguments(args, 1)?;
+            data[0] = board.digital_read(args[0])? as u8;
+            Ok(1)
+        },
+        PWM_WRITE => {
+            let args = arguments(args, 2)?;
+            board.pwm_write(args[0], args[1])?;
+            Ok(0)
+        },
+        ANALOG_READ => {
+            let args = arguments(args, 1)?;
+            let value = board.analog_read(args[0])?;
+            data[0] = value as u8;
+            data[1] = (value >> 8) as u8;
+            Ok(2)
+        },
+        TIMER_COUNT => {
+            let args = arguments(args, 1)?;
+            let count = board.timer_count(args[0])?;
+            data[0] = count as u8;
+            data[1] = (count >> 8) as u8;
+            Ok(2)
+        },
+        TIMER_COMPARE => {
+            let args = arguments(args, 4)?;
+            if args[1] < 1 || args[1] > 4 {
+                return Err(Error::Argument);
+            }
+            let value = args[2] as u16 | (args[3] as u16) << 8;
+            board.timer_compare(args[0], args[1], value)?;
+            Ok(0)
+        },
+        TIMER_RUN => {
+            let args = arguments(args, 2)?;
+            board.timer_run(args[0], flag(args[1])?)?;
+            Ok(0)
+        },
+        _ => Err(Error::Opcode),
+    }
+}
+
+/// Checks that exactly `len` argument bytes were sent
+fn arguments(args: &[u8], len: usize) -> Result<&[u8]> {
+    if args.len() == len {
+        Ok(args)
+    } else {
+        Err(Error::Length)
+    }
+}
+
+/// Decodes a boolean argument
+fn flag(byte: u8) -> Result<bool> {
+    match byte {
+        0 => Ok(false),
+        1 => Ok(true),
+        _ => Err(Error::Argument),
+    }
+}
+
+/// Returns a request as the host sends it: `payload` and its CRC, COBS
+/// encoded
+#[cfg(test)]
+pub(crate) fn frame(payload: &[u8]) -> ::std::vec::Vec<u8> {
+    let mut packet = payload.to_vec();
+    let crc = crc16(payload);
+    packet.push(crc as u8);
+    packet.push((crc >> 8) as u8);
+    cobs::Encoder::new(&packet).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    // one pin and one timer, the pin reads back what was written
+    struct TestBoard {
+        level: bool,
+        duty_cycle: u8,
+        compare: (u8, u16),
+        running: bool,
+    }
+
+    impl TestBoard {
+        fn new() -> TestBoard {
+            TestBoard { level: false, duty_cycle: 0, compare: (0, 0), running: false }
+        }
+    }
+
+    impl Board for TestBoard {
+        fn digital_write(&mut self, pin: u8, high: bool) -> Result<()> {
+            if pin != 0 {
+                return Err(Error::Index);
+            }
+            self.level = high;
+            Ok(())
+        }
+
+        fn digital_read(&mut self, pin: u8) -> Result<bool> {
+            if pin != 0 {
+                return Err(Error::Index);
+            }
+            Ok(self.level)
+        }
+
+        fn pwm_write(&mut self, pin: u8, duty_cycle: u8) -> Result<()> {
+            if pin != 0 {
+                return Err(Error::Index);
+            }
+            self.duty_cycle = duty_cycle;
+            Ok(())
+        }
+
+        fn analog_read(&mut self, _pin: u8) -> Result<u16> {
+            Err(Error::Opcode)
+        }
+
+        fn timer_count(&mut self, timer: u8) -> Result<u16> {
+            if timer != 0 {
+                return Err(Error::Index);
+            }
+            Ok(0x1234)
+        }
+
+        fn timer_compare(&mut self, timer: u8, channel: u8, value: u16) -> Result<()> {
+            if timer != 0 {
+                return Err(Error::Index);
+            }
+            self.compare = (channel, value);
+            Ok(())
+        }
+
+        fn timer_run(&mut self, timer: u8, running: bool) -> Result<()> {
+            if timer != 0 {
+                return Err(Error::Index);
+            }
+            self.running = running;
+            Ok(())
+        }
+    }
+
+    impl Transport for Vec<u8> {
+        fn send(&mut self, byte: u8) {
+            self.push(byte);
+        }
+    }
+
+    fn feed(board: &mut TestBoard, wire: &[u8]) -> Vec<u8> {
+        let mut server = Server::new([0; MAX_PAYLOAD + 2]);
+        let mut out = Vec::new();
+        for &byte in wire {
+            server.feed(byte, board, &mut out);
+        }
+        out
+    }
+
+    fn call(board: &mut TestBoard, payload: &[u8]) -> Vec<u8> {
+        feed(board, &frame(payload))
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(&[]), 0xFFFF);
+    }
+
+    #[test]
+    fn longest_response_fits() {
+        let mut board = TestBoard::new();
+        let mut request = [0; MAX_PAYLOAD];
+        request[1] = PING;
+        assert_eq!(call(&mut board, &request).len(), MAX_FRAME);
+    }
+
+    #[test]
+    fn ping() {
+        let mut board = TestBoard::new();
+        assert_eq!(call(&mut board, &[7, PING, 1, 2, 3]), frame(&[7, 0, 1, 2, 3]));
+    }
+
+    #[test]
+    fn digital() {
+        let mut board = TestBoard::new();
+        assert_eq!(call(&mut board, &[1, DIGITAL_WRITE, 0, 1]), frame(&[1, 0]));
+        assert!(board.level);
+        assert_eq!(call(&mut board, &[2, DIGITAL_READ, 0]), frame(&[2, 0, 1]));
+    }
+
+    #[test]
+    fn timers() {
+        let mut board = TestBoard::new();
+        assert_eq!(call(&mut board, &[1, TIMER_COUNT, 0]), frame(&[1, 0, 0x34, 0x12]));
+        assert_eq!(call(&mut board, &[2, TIMER_COMPARE, 0, 3, 0xCD, 0xAB]), frame(&[2, 0]));
+        assert_eq!(board.compare, (3, 0xABCD));
+        assert_eq!(call(&mut board, &[3, TIMER_RUN, 0, 1]), frame(&[3, 0]));
+        assert!(board.running);
+    }
+
+    #[test]
+    fn errors() {
+        let mut board = TestBoard::new();
+        let status = |board: &mut TestBoard, payload: &[u8], error: Error| {
+            assert_eq!(call(board, payload), frame(&[payload[0], error as u8]));
+        };
+
+        status(&mut board, &[1, 0x7F, 0], Error::Opcode);
+        status(&mut board, &[2, ANALOG_READ, 0], Error::Opcode);
+        status(&mut board, &[3, DIGITAL_WRITE, 0], Error::Length);
+        status(&mut board, &[4, DIGITAL_WRITE, 1, 1], Error::Index);
+        status(&mut board, &[5, DIGITAL_WRITE, 0, 2], Error::Argument);
+        status(&mut board, &[6, TIMER_COMPARE, 0, 5, 0, 0], Error::Argument);
+        status(&mut board, &[7, PWM_WRITE, 3, 128], Error::Index);
+        assert_eq!(board.duty_cycle, 0);
+    }
+
+    #[test]
+    fn bad_checksum() {
+        let mut board = TestBoard::new();
+        let mut packet = [9, DIGITAL_WRITE, 0, 1].to_vec();
+        let crc = crc16(&packet) ^ 1;
+        packet.push(crc as u8);
+        packet.push((crc >> 8) as u8);
+        let wire: Vec<u8> = cobs::Encoder::new(&packet).collect();
+
+        assert_eq!(feed(&mut board, &wire), frame(&[9, Error::Checksum as u8]));
+        assert!(!board.level);
+    }
+
+    #[test]
+    fn short_and_broken_frames() {
+        let mut board = TestBoard::new();
+        // too short to carry a sequence number, opcode and CRC
+        assert!(feed(&mut board, &[0x03, 1, 2, 0x00]).is_empty());
+        // COBS framing error, then a good request
+        let mut wire = [0x05, 1, 0x00].to_vec();
+        wire.extend(frame(&[4, PING]));
+        assert_eq!(feed(&mut board, &wire), frame(&[4, 0]));
+    }
+}
@@ -0,0 +1,415 @@
+//! Command shell
+//!
+//! Line editing command interpreter for field debugging over a serial port.
+//! Supports backspace, Ctrl-C, CR, LF or CRLF line endings and a small
+//! history browsed with the up and down arrow keys. Commands are registered
+//! in a static table, `help` lists them.
+//!
+//! `feed` writes all the output of a byte at once. A driver with a bounded
+//! output queue calls `input` instead and writes a `help` listing one
+//! `help_line` at a time, as `bluepill::shell::poll` does.
+//!
+//! ``` ignore
+//! static COMMANDS: [Command; 1] = [
+//!     Command { name: "led", help: "led on|off", run: led },
+//! ];
+//! static SHELL: Resource<RefCell<Shell>, C1> =
+//!     Resource::new(RefCell::new(Shell::new(&COMMANDS)));
+//! ```
+
+// `dyn` isn't available on the toolchain the board crate is built with
+#![allow(unknown_lints, bare_trait_objects)]
+
+use core::fmt;
+use core::str;
+
+/// Longest command line
+pub const LINE_SIZE: usize = 64;
+/// Number of lines kept in the history
+pub const HISTORY_SIZE: usize = 4;
+/// Most arguments a command line can have, including the command name
+pub const MAX_ARGS: usize = 8;
+/// Prompt printed before each line
+pub const PROMPT: &str = "> ";
+
+/// A shell command
+pub struct Command {
+    /// name typed to run the command
+    pub name: &'static str,
+    /// one line description shown by `help`
+    pub help: &'static str,
+    /// handler, `args[0]` is the command name
+    pub run: fn(args: &[&str], out: &mut fmt::Write),
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    // got ESC
+    Esc,
+    // got ESC [
+    Csi,
+}
+
+/// Command shell
+pub struct Shell {
+    commands: &'static [Command],
+    line: [u8; LINE_SIZE],
+    len: usize,
+    history: [[u8; LINE_SIZE]; HISTORY_SIZE],
+    history_len: [usize; HISTORY_SIZE],
+    // number of lines stored in the history
+    stored: usize,
+    // slot the next line goes into
+    next: usize,
+    // how far back in the history the user has browsed, 0 is the new line
+    browse: usize,
+    // the line being typed before browsing the history
+    draft: [u8; LINE_SIZE],
+    draft_len: usize,
+    // next command `help` lists, the prompt follows the last one
+    help: Option<usize>,
+    escape: Escape,
+    // the last byte was CR, a following LF is part of the same line ending
+    cr: bool,
+}
+
+impl Shell {
+    /// returns a shell running `commands`
+    pub const fn new(commands: &'static [Command]) -> Shell {
+        Shell {
+            commands,
+            line: [0; LINE_SIZE],
+            len: 0,
+            history: [[0; LINE_SIZE]; HISTORY_SIZE],
+            history_len: [0; HISTORY_SIZE],
+            stored: 0,
+            next: 0,
+            browse: 0,
+            draft: [0; LINE_SIZE],
+            draft_len: 0,
+            help: None,
+            escape: Escape::None,
+            cr: false,
+        }
+    }
+
+    /// Prints the prompt
+    pub fn prompt(&self, out: &mut fmt::Write) {
+        out.write_str(PROMPT).ok();
+    }
+
+    /// Feeds a received byte, echoing and running commands on `out`
+    ///
+    /// `out` has to take all the output, `help` is written at once
+    pub fn feed(&mut self, byte: u8, out: &mut fmt::Write) {
+        self.input(byte, out);
+        while self.help.is_some() {
+            self.help_line(out);
+        }
+    }
+
+    /// Feeds a received byte like `feed`, but only starts a `help` listing
+    ///
+    /// Write the listing with `help_line` until `help_len` returns `None`,
+    /// before feeding the next byte
+    pub fn input(&mut self, byte: u8, out: &mut fmt::Write) {
+        let cr = self.cr;
+        self.cr = byte == b'\r';
+
+        match self.escape {
+            Escape::Esc => {
+                self.escape = if byte == b'[' { Escape::Csi } else { Escape::None };
+                return;
+            },
+            Escape::Csi => {
+                self.escape = Escape::None;
+                let browse = self.browse;
+                match byte {
+                    b'A' => self.recall(browse + 1, out),
+                    b'B' if browse > 0 => self.recall(browse - 1, out),
+                    _ => {},
+                }
+                return;
+            },
+            Escape::None => {},
+        }
+
+        match byte {
+            b'\n' if cr => {},
+            b'\r' | b'\n' => {
+                out.write_str("\r\n").ok();
+                self.execute(out);
+                self.save();
+                self.len = 0;
+                self.browse = 0;
+                // `help` prints the prompt after the last command
+                if self.help.is_none() {
+                    self.prompt(out);
+                }
+            },
+            // backspace and delete
+            0x08 | 0x7F if self.len > 0 => {
+                self.len -= 1;
+                out.write_str("\x08 \x08").ok();
+            },
+            // Ctrl-C
+            0x03 => {
+                out.write_str("^C\r\n").ok();
+                self.len = 0;
+                self.browse = 0;
+                self.prompt(out);
+            },
+            0x1B => self.escape = Escape::Esc,
+            // printable ASCII, dropped once the line is full
+            _ if byte < 0x80 && !(byte as char).is_control() && self.len < LINE_SIZE => {
+                self.line[self.len] = byte;
+                self.len += 1;
+                out.write_char(byte as char).ok();
+            },
+            _ => {},
+        }
+    }
+
+    /// Returns the length of the next line of a pending `help` listing, or
+    /// `None` if there is none
+    ///
+    /// A line can be longer than what `help_line` is allowed to write, the
+    /// output is cut short then
+    pub fn help_len(&self) -> Option<usize> {
+        self.help.map(|index| match self.commands.get(index) {
+            // "name - help\r\n"
+            Some(command) => command.name.len() + command.help.len() + 5,
+            None => PROMPT.len(),
+        })
+    }
+
+    /// Writes the next line of a pending `help` listing, the prompt follows
+    /// the last command
+    pub fn help_line(&mut self, out: &mut fmt::Write) {
+        let index = match self.help {
+            Some(index) => index,
+            None => return,
+        };
+
+        match self.commands.get(index) {
+            Some(command) => {
+                write!(out, "{} - {}\r\n", command.name, command.help).ok();
+                self.help = Some(index + 1);
+            },
+            None => {
+                self.prompt(out);
+                self.help = None;
+            },
+        }
+    }
+
+    /// Tokenizes the current line and runs the matching command
+    fn execute(&mut self, out: &mut fmt::Write) {
+        // only printable ASCII is ever stored in the line
+        let line = match str::from_utf8(&self.line[..self.len]) {
+            Ok(line) => line,
+            Err(_) => return,
+        };
+
+        let mut args = [""; MAX_ARGS];
+        let mut argc = 0;
+        for arg in line.split_whitespace() {
+            if argc == MAX_ARGS {
+                writeln!(out, "too many arguments\r").ok();
+                return;
+            }
+            args[argc] = arg;
+            argc += 1;
+        }
+
+        if argc == 0 {
+            return;
+        }
+
+        if args[0] == "help" {
+            self.help = Some(0);
+            return;
+        }
+
+        match self.commands.iter().find(|command| command.name == args[0]) {
+            Some(command) => (command.run)(&args[..argc], out),
+            None => { writeln!(out, "unknown command: {}\r", args[0]).ok(); },
+        }
+    }
+
+    /// Stores the current line in the history
+    fn save(&mut self) {
+        if self.len == 0 {
+            return;
+        }
+
+        self.history[self.next] = self.line;
+        self.history_len[self.next] = self.len;
+        self.next = (self.next + 1) % HISTORY_SIZE;
+        if self.stored < HISTORY_SIZE {
+            self.stored += 1;
+        }
+    }
+
+    /// Replaces the current line with the history entry `back` lines ago
+    fn recall(&mut self, back: usize, out: &mut fmt::Write) {
+        if back > self.stored {
+            return;
+        }
+        if self.browse == 0 {
+            // keep the line being typed, it comes back after the newest entry
+            self.draft = self.line;
+            self.draft_len = self.len;
+        }
+        self.browse = back;
+
+        if back == 0 {
+            self.line = self.draft;
+            self.len = self.draft_len;
+        } else {
+            let slot = (self.next + HISTORY_SIZE - back) % HISTORY_SIZE;
+            self.line = self.history[slot];
+            self.len = self.history_len[slot];
+        }
+
+        // redraw: carriage return, erase the line, prompt and the recalled line
+        out.write_str("\r\x1b[K").ok();
+        self.prompt(out);
+        if let Ok(line) = str::from_utf8(&self.line[..self.len]) {
+            out.write_str(line).ok();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::string::String;
+
+    use super::*;
+
+    static COMMANDS: [Command; 2] = [
+        Command { name: "echo", help: "print the arguments", run: echo },
+        Command { name: "ok", help: "print ok", run: ok },
+    ];
+
+    fn echo(args: &[&str], out: &mut fmt::Write) {
+        for arg in &args[1..] {
+            write!(out, "[{}]", arg).ok();
+        }
+        out.write_str("\r\n").ok();
+    }
+
+    fn ok(_: &[&str], out: &mut fmt::Write) {
+        out.write_str("ok\r\n").ok();
+    }
+
+    fn feed(shell: &mut Shell, input: &[u8]) -> String {
+        let mut out = String::new();
+        for &byte in input {
+            shell.feed(byte, &mut out);
+        }
+        out
+    }
+
+    #[test]
+    fn runs_commands() {
+        let mut shell = Shell::new(&COMMANDS);
+        assert_eq!(feed(&mut shell, b"echo  a b\r"), "echo  a b\r\n[a][b]\r\n> ");
+        assert_eq!(feed(&mut shell, b"nope\r"), "nope\r\nunknown command: nope\r\n> ");
+        // an empty line just prints the prompt again
+        assert_eq!(feed(&mut shell, b"\r"), "\r\n> ");
+    }
+
+    #[test]
+    fn line_endings() {
+        let mut shell = Shell::new(&COMMANDS);
+        // CRLF is a single line ending, LF and CR on their own end a line too
+        assert_eq!(feed(&mut shell, b"ok\r\n"), "ok\r\nok\r\n> ");
+        assert_eq!(feed(&mut shell, b"ok\n"), "ok\r\nok\r\n> ");
+        assert_eq!(feed(&mut shell, b"ok\r\r\n"), "ok\r\nok\r\n> \r\n> ");
+    }
+
+    #[test]
+    fn editing() {
+        let mut shell = Shell::new(&COMMANDS);
+        assert_eq!(feed(&mut shell, b"okx\x7F"), "okx\x08 \x08");
+        // control characters and non-ASCII bytes are dropped
+        assert_eq!(feed(&mut shell, b"\x01\xC3\r"), "\r\nok\r\n> ");
+        assert_eq!(feed(&mut shell, b"ec\x03"), "ec^C\r\n> ");
+        assert_eq!(feed(&mut shell, b"\r"), "\r\n> ");
+    }
+
+    #[test]
+    fn history() {
+        let mut shell = Shell::new(&COMMANDS);
+        feed(&mut shell, b"echo 1\r");
+        feed(&mut shell, b"echo 2\r");
+
+        assert_eq!(feed(&mut shell, b"\x1b[A"), "\r\x1b[K> echo 2");
+        assert_eq!(feed(&mut shell, b"\x1b[A"), "\r\x1b[K> echo 1");
+        // nothing older
+        assert_eq!(feed(&mut shell, b"\x1b[A"), "");
+        assert_eq!(feed(&mut shell, b"\x1b[B"), "\r\x1b[K> echo 2");
+        assert_eq!(feed(&mut shell, b"\r"), "\r\n[2]\r\n> ");
+    }
+
+    #[test]
+    fn history_restores_draft() {
+        let mut shell = Shell::new(&COMMANDS);
+        feed(&mut shell, b"ok\r");
+        feed(&mut shell, b"ech");
+
+        assert_eq!(feed(&mut shell, b"\x1b[A"), "\r\x1b[K> ok");
+        assert_eq!(feed(&mut shell, b"\x1b[B"), "\r\x1b[K> ech");
+        assert_eq!(feed(&mut shell, b"o x\r"), "o x\r\n[x]\r\n> ");
+    }
+
+    #[test]
+    fn help() {
+        let mut shell = Shell::new(&COMMANDS);
+        assert_eq!(
+            feed(&mut shell, b"help\r"),
+            "help\r\necho - print the arguments\r\nok - print ok\r\n> "
+        );
+    }
+
+    #[test]
+    fn help_one_line_at_a_time() {
+        let mut shell = Shell::new(&COMMANDS);
+        let mut out = String::new();
+        for &byte in b"help\r" {
+            shell.input(byte, &mut out);
+        }
+        assert_eq!(out, "help\r\n");
+
+        for &expected in &["echo - print the arguments\r\n", "ok - print ok\r\n", "> "] {
+            assert_eq!(shell.help_len(), Some(expected.len()));
+            let mut line = String::new();
+            shell.help_line(&mut line);
+            assert_eq!(line, expected);
+        }
+        assert_eq!(shell.help_len(), None);
+    }
+
+    #[test]
+    fn too_many_arguments() {
+        let mut shell = Shell::new(&COMMANDS);
+        assert_eq!(
+            feed(&mut shell, b"echo 1 2 3 4 5 6 7 8\r"),
+            "echo 1 2 3 4 5 6 7 8\r\ntoo many arguments\r\n> "
+        );
+        assert_eq!(feed(&mut shell, b"echo 1 2 3 4 5 6 7\r"),
+                   "echo 1 2 3 4 5 6 7\r\n[1][2][3][4][5][6][7]\r\n> ");
+    }
+
+    #[test]
+    fn long_lines_are_cut() {
+        let mut shell = Shell::new(&COMMANDS);
+        let mut input = [b'x'; LINE_SIZE + 2];
+        input[0] = b'o';
+        input[1] = b'k';
+        let out = feed(&mut shell, &input);
+        assert_eq!(out.len(), LINE_SIZE);
+    }
+}
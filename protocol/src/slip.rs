@@ -0,0 +1,225 @@
+//! Serial Line Internet Protocol (SLIP) framing, RFC 1055
+//!
+//! Every packet ends with an END byte. END and ESC bytes inside the packet
+//! are replaced by two byte escape sequences, all other bytes go out as they
+//! are. Text stays readable on a terminal, but a packet full of END bytes
+//! doubles in size, so COBS is the better choice for arbitrary binary data.
+
+use packet::Packet;
+
+/// Packet delimiter
+pub const END: u8 = 0xC0;
+/// Escape byte
+pub const ESC: u8 = 0xDB;
+/// Escaped END
+pub const ESC_END: u8 = 0xDC;
+/// Escaped ESC
+pub const ESC_ESC: u8 = 0xDD;
+
+/// Specialized `Result` type
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// An error
+#[derive(Debug, PartialEq)]
+pub enum Error {
+    /// ESC followed by something other than ESC_END or ESC_ESC
+    Framing,
+    /// the packet doesn't fit in the decoder buffer
+    Overflow,
+}
+
+#[derive(Clone, Copy)]
+enum EncoderState {
+    Start,
+    Data,
+    Escaped(u8),
+    Done,
+}
+
+/// Encodes a packet, yielding the encoded bytes
+///
+/// The packet is preceded by an END byte too, which flushes any line noise
+/// received by the other end
+pub struct Encoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+    state: EncoderState,
+}
+
+impl<'a> Encoder<'a> {
+    /// returns an encoder for `data`
+    pub fn new(data: &'a [u8]) -> Encoder<'a> {
+        Encoder { data, pos: 0, state: EncoderState::Start }
+    }
+}
+
+impl<'a> Iterator for Encoder<'a> {
+    type Item = u8;
+
+    fn next(&mut self) -> Option<u8> {
+        match self.state {
+            EncoderState::Start => {
+                self.state = EncoderState::Data;
+                Some(END)
+            },
+            EncoderState::Data => {
+                match self.data.get(self.pos) {
+                    Some(&byte) => {
+                        self.pos += 1;
+                        match byte {
+                            END => {
+                                self.state = EncoderState::Escaped(ESC_END);
+                                Some(ESC)
+                            },
+                            ESC => {
+                                self.state = EncoderState::Escaped(ESC_ESC);
+                                Some(ESC)
+                            },
+                            _ => Some(byte),
+                        }
+                    },
+                    None => {
+                        self.state = EncoderState::Done;
+                        Some(END)
+                    },
+                }
+            },
+            EncoderState::Escaped(byte) => {
+                self.state = EncoderState::Data;
+                Some(byte)
+            },
+            EncoderState::Done => None,
+        }
+    }
+}
+
+/// Incremental decoder
+///
+/// Unescapes the bytes as they arrive and collects the packet in `buffer`
+pub struct Decoder<A> {
+    packet: Packet<A>,
+    escaped: bool,
+}
+
+impl<A> Decoder<A> {
+    /// returns a decoder writing into `buffer`, which must hold the largest
+    /// unescaped packet
+    pub const fn new(buffer: A) -> Decoder<A> {
+        Decoder { packet: Packet::new(buffer), escaped: false }
+    }
+}
+
+impl<A> Decoder<A>
+where
+    A: AsMut<[u8]> + AsRef<[u8]>,
+{
+    /// Feeds a received byte
+    ///
+    /// Returns the packet at its END byte. Empty packets, such as the END
+    /// that `Encoder` sends first, are skipped. An invalid escape sequence is
+    /// a `Framing` error and drops the packet.
+    pub fn feed(&mut self, byte: u8) -> Result<Option<&[u8]>> {
+        if byte == END {
+            let escaped = self.escaped;
+            self.escaped = false;
+
+            return match self.packet.finish() {
+                None => Ok(None),
+                Some(packet) => if packet.is_empty() {
+                    Ok(None)
+                } else if escaped {
+                    // END right after ESC
+                    Err(Error::Framing)
+                } else {
+                    Ok(Some(packet))
+                },
+            };
+        }
+
+        if self.packet.is_discarding() {
+            return Ok(None);
+        }
+
+        let byte = if self.escaped {
+            self.escaped = false;
+            match byte {
+                ESC_END => END,
+                ESC_ESC => ESC,
+                _ => {
+                    self.packet.discard();
+                    return Err(Error::Framing);
+                },
+            }
+        } else if byte == ESC {
+            self.escaped = true;
+            return Ok(None);
+        } else {
+            byte
+        };
+
+        if self.packet.push(byte) {
+            Ok(None)
+        } else {
+            Err(Error::Overflow)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use super::*;
+
+    #[test]
+    fn escapes() {
+        // RFC 1055: END becomes ESC ESC_END, ESC becomes ESC ESC_ESC
+        let data = [0x01, 0xC0, 0xDB, 0x02];
+        let encoded: Vec<u8> = Encoder::new(&data).collect();
+        assert_eq!(encoded, [0xC0, 0x01, 0xDB, 0xDC, 0xDB, 0xDD, 0x02, 0xC0]);
+
+        let mut decoder = Decoder::new([0; 8]);
+        let (last, rest) = encoded.split_last().unwrap();
+        for &byte in rest {
+            assert_eq!(decoder.feed(byte), Ok(None));
+        }
+        assert_eq!(decoder.feed(*last), Ok(Some(&data[..])));
+    }
+
+    #[test]
+    fn plain_bytes() {
+        let encoded: Vec<u8> = Encoder::new(b"hi").collect();
+        assert_eq!(encoded, [0xC0, b'h', b'i', 0xC0]);
+    }
+
+    #[test]
+    fn invalid_escape() {
+        let mut decoder = Decoder::new([0; 8]);
+        assert_eq!(decoder.feed(0x01), Ok(None));
+        assert_eq!(decoder.feed(ESC), Ok(None));
+        assert_eq!(decoder.feed(0x05), Err(Error::Framing));
+        assert_eq!(decoder.feed(0x02), Ok(None));
+        // the broken packet is dropped, the next one decodes
+        assert_eq!(decoder.feed(END), Ok(None));
+        assert_eq!(decoder.feed(0x03), Ok(None));
+        assert_eq!(decoder.feed(END), Ok(Some(&[0x03][..])));
+    }
+
+    #[test]
+    fn end_after_escape() {
+        let mut decoder = Decoder::new([0; 8]);
+        assert_eq!(decoder.feed(0x01), Ok(None));
+        assert_eq!(decoder.feed(ESC), Ok(None));
+        assert_eq!(decoder.feed(END), Err(Error::Framing));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut decoder = Decoder::new([0; 2]);
+        assert_eq!(decoder.feed(0x01), Ok(None));
+        assert_eq!(decoder.feed(0x02), Ok(None));
+        assert_eq!(decoder.feed(0x03), Err(Error::Overflow));
+        assert_eq!(decoder.feed(0x04), Ok(None));
+        assert_eq!(decoder.feed(END), Ok(None));
+    }
+}
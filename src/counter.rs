@@ -0,0 +1,67 @@
+//! Frequency counter
+//!
+//! One timer counts pulses on its ETR or TIx input while a second timer
+//! generates periodic update events, the count is read back on every update
+//! event of the gate timer.
+//!
+//! - ETR - PA0 (TIM2), PA12 (TIM1)
+//! - TI1 - PA0 (TIM2), PA6 (TIM3), PB6 (TIM4), PA8 (TIM1)
+//!
+//! The counter is 16 bits wide so `gate_hz` has to be higher than the
+//! highest measured frequency divided by 65536.
+
+use stm32f103xx::Rcc;
+
+use timer::{halTimer, CountInput, Timer};
+
+/// Frequency counter
+pub struct FrequencyCounter {
+    last: u16,
+    hz: u32,
+    clock: u32,
+    gate_ticks: u32,
+}
+
+impl FrequencyCounter {
+    /// returns a new frequency counter
+    pub const fn new() -> FrequencyCounter {
+        FrequencyCounter { last: 0, hz: 0, clock: 0, gate_ticks: 0 }
+    }
+
+    /// Initializes the counting timer and starts the gate timer with update
+    /// events at roughly `gate_hz`
+    pub fn init(&mut self, rcc: &Rcc, counter: &Timer, input: CountInput, gate: &Timer, gate_hz: u32) {
+        counter.count_external(rcc, input);
+
+        self.clock = gate.clock_speed(rcc);
+        gate.init(rcc, self.clock / gate_hz);
+
+        // use the period that was actually programmed so rounding in the
+        // prescaler doesn't show up in the result
+        let psc = gate.timer.psc.read().psc().bits() as u32;
+        let arr = gate.timer.arr.read().arr().bits() as u32;
+        self.gate_ticks = (psc + 1) * (arr + 1);
+
+        self.last = counter.count();
+        self.hz = 0;
+        gate.resume();
+    }
+
+    /// Reads the counter and updates the measured frequency
+    ///
+    /// Call this from the gate timer interrupt after clearing its update flag,
+    /// returns the frequency in Hz
+    pub fn sample(&mut self, counter: &Timer) -> u32 {
+        let count = counter.count();
+        let pulses = count.wrapping_sub(self.last) as u64;
+        self.last = count;
+
+        self.hz = (pulses * self.clock as u64 / self.gate_ticks as u64) as u32;
+        self.hz
+    }
+
+    /// Returns the last measured frequency in Hz
+    pub fn frequency(&self) -> u32 {
+        self.hz
+    }
+}
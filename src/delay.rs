@@ -0,0 +1,72 @@
+//! Blocking delays
+//!
+//! Uses the SysTick timer clocked from SYSCLK. The clock speed is read back
+//! from the RCC once per call, so delays stay correct after `frequency::init`
+
+use cortex_m::peripheral::{Syst, SystClkSource};
+use stm32f103xx::Rcc;
+
+use frequency;
+
+/// SysTick is a 24 bit down counter
+const MAX_RELOAD: u32 = 0x00FF_FFFF;
+
+/// Blocking delay provider
+pub struct Delay<'a> {
+    /// SysTick timer
+    pub syst: &'a Syst,
+    /// used to read the current SYSCLK
+    pub rcc: &'a Rcc,
+}
+
+impl<'a> Delay<'a> {
+    /// returns a new delay provider
+    pub fn new(syst: &'a Syst, rcc: &'a Rcc) -> Delay<'a> {
+        Delay { syst, rcc }
+    }
+
+    /// Blocks for `us` microseconds
+    pub fn delay_us(&self, us: u32) {
+        let ticks_per_us = self.sysclk() / 1_000_000;
+        self.wait_ticks(us as u64 * ticks_per_us as u64);
+    }
+
+    /// Blocks for `ms` milliseconds
+    pub fn delay_ms(&self, ms: u32) {
+        let ticks_per_ms = self.sysclk() / 1_000;
+        self.wait_ticks(ms as u64 * ticks_per_ms as u64);
+    }
+
+    fn sysclk(&self) -> u32 {
+        frequency::ClockSpeeds::get(self.rcc).sysclk
+    }
+
+    /// Blocks for `ticks` SYSCLK cycles
+    ///
+    /// Delays longer than the 24 bit counter are split into chunks, a short
+    /// delay programs SysTick once
+    fn wait_ticks(&self, ticks: u64) {
+        self.syst.set_clock_source(SystClkSource::Core);
+
+        let mut remaining = ticks;
+        // a reload value of 0 disables the counter, so a final single tick
+        // is dropped
+        while remaining > 1 {
+            let chunk = if remaining > MAX_RELOAD as u64 + 1 {
+                MAX_RELOAD + 1
+            } else {
+                remaining as u32
+            };
+
+            // the counter wraps after RELOAD + 1 cycles
+            self.syst.set_reload(chunk - 1);
+            self.syst.clear_current();
+            self.syst.enable_counter();
+
+            while !self.syst.has_wrapped() {}
+
+            self.syst.disable_counter();
+            remaining -= chunk as u64;
+        }
+    }
+}
@@ -0,0 +1,249 @@
+//! DMA channels
+//!
+//! Thin wrapper over the seven channels of DMA1, shared by the peripherals
+//! that can issue DMA requests. Peripheral request mapping:
+//!
+//! - Channel 2 - TIM2_UP, USART3_TX
+//! - Channel 3 - TIM3_UP, USART3_RX
+//! - Channel 4 - USART1_TX
+//! - Channel 5 - TIM1_UP, USART1_RX
+//! - Channel 6 - USART2_RX
+//! - Channel 7 - TIM4_UP, USART2_TX
+
+use stm32f103xx::{dma1, Rcc};
+
+// CCR bits
+const EN: u32 = 1 << 0;
+const TCIE: u32 = 1 << 1;
+const HTIE: u32 = 1 << 2;
+const TEIE: u32 = 1 << 3;
+const DIR: u32 = 1 << 4;
+const CIRC: u32 = 1 << 5;
+const MINC: u32 = 1 << 7;
+
+/// Transfer direction
+#[derive(Clone, Copy)]
+pub enum Direction {
+    /// read from the peripheral, write to memory
+    PeripheralToMemory,
+    /// read from memory, write to the peripheral
+    MemoryToPeripheral,
+}
+
+/// Size of each transferred item
+#[derive(Clone, Copy)]
+pub enum Size {
+    /// 8 bits
+    Byte = 0b00,
+    /// 16 bits
+    HalfWord = 0b01,
+    /// 32 bits
+    Word = 0b10,
+}
+
+/// DMA events
+#[derive(Clone, Copy)]
+pub enum Event {
+    /// half of the transfer is done
+    HalfTransfer,
+    /// the transfer is done
+    TransferComplete,
+    /// bus error during the transfer
+    TransferError,
+}
+
+/// Transfer configuration
+pub struct Transfer {
+    /// peripheral register address
+    pub peripheral: u32,
+    /// memory buffer address, incremented after every item
+    pub memory: u32,
+    /// number of items
+    pub len: u16,
+    /// transfer direction
+    pub direction: Direction,
+    /// item size, used on both the peripheral and the memory side
+    pub size: Size,
+    /// restart from the beginning of the buffer when done
+    pub circular: bool,
+}
+
+/// DMA1 channel
+pub struct Channel<'a> {
+    /// dma controller
+    pub dma: &'a dma1::RegisterBlock,
+    number: u8,
+}
+
+impl<'a> Channel<'a> {
+    /// returns DMA channel `number`, or `None` if it isn't 1 to 7
+    pub fn new(number: u8, dma: &'a dma1::RegisterBlock) -> Option<Channel<'a>> {
+        match number {
+            1...7 => Some(Channel { dma, number }),
+            _ => None,
+        }
+    }
+
+    /// Returns the channel number, 1 to 7
+    pub fn number(&self) -> u8 {
+        self.number
+    }
+
+    /// Powers up the DMA controller
+    pub fn init(&self, rcc: &Rcc) {
+        rcc.ahbenr.modify(|_, w| w.dma1en().enabled());
+    }
+
+    /// Starts a transfer, any transfer in progress on this channel is stopped
+    ///
+    /// # Safety
+    ///
+    /// The DMA controller reads and writes the raw addresses in `transfer`.
+    /// `peripheral` has to be a data register of the peripheral that issues
+    /// requests on this channel, and `memory` has to point to `len` items that
+    /// stay valid, and aren't accessed otherwise, until the transfer is done
+    /// or stopped.
+    pub unsafe fn start(&self, transfer: &Transfer) {
+        self.stop();
+        self.clear_flags();
+
+        let mut ccr = MINC | (transfer.size as u32) << 8 | (transfer.size as u32) << 10;
+        if let Direction::MemoryToPeripheral = transfer.direction {
+            ccr |= DIR;
+        }
+        if transfer.circular {
+            ccr |= CIRC;
+        }
+
+        // keep the interrupt enables set with `listen`
+        ccr |= self.ccr() & (TCIE | HTIE | TEIE);
+
+        self.set_cpar(transfer.peripheral);
+        self.set_cmar(transfer.memory);
+        self.set_cndtr(transfer.len as u32);
+        self.set_ccr(ccr);
+        self.set_ccr(ccr | EN);
+    }
+
+    /// Stops the transfer
+    pub fn stop(&self) {
+        let ccr = self.ccr();
+        self.set_ccr(ccr & !EN);
+    }
+
+    /// Returns the number of items left to transfer
+    pub fn remaining(&self) -> u16 {
+        match self.number {
+            1 => self.dma.cndtr1.read().bits() as u16,
+            2 => self.dma.cndtr2.read().bits() as u16,
+            3 => self.dma.cndtr3.read().bits() as u16,
+            4 => self.dma.cndtr4.read().bits() as u16,
+            5 => self.dma.cndtr5.read().bits() as u16,
+            6 => self.dma.cndtr6.read().bits() as u16,
+            _ => self.dma.cndtr7.read().bits() as u16,
+        }
+    }
+
+    /// Enables the interrupt for `event`
+    pub fn listen(&self, event: Event) {
+        let ccr = self.ccr();
+        self.set_ccr(ccr | Self::enable_bit(event));
+    }
+
+    /// Disables the interrupt for `event`
+    pub fn unlisten(&self, event: Event) {
+        let ccr = self.ccr();
+        self.set_ccr(ccr & !Self::enable_bit(event));
+    }
+
+    /// Returns `true` if `event` has occurred since the flags were cleared
+    pub fn is_set(&self, event: Event) -> bool {
+        let flags = self.dma.isr.read().bits() >> self.flag_shift();
+        let bit = match event {
+            Event::TransferComplete => 1 << 1,
+            Event::HalfTransfer => 1 << 2,
+            Event::TransferError => 1 << 3,
+        };
+        flags & bit != 0
+    }
+
+    /// Clears the flags of this channel
+    pub fn clear_flags(&self) {
+        let shift = self.flag_shift();
+        self.dma.ifcr.write(|w| unsafe { w.bits(0b1111 << shift) });
+    }
+
+    fn enable_bit(event: Event) -> u32 {
+        match event {
+            Event::HalfTransfer => HTIE,
+            Event::TransferComplete => TCIE,
+            Event::TransferError => TEIE,
+        }
+    }
+
+    fn flag_shift(&self) -> u32 {
+        4 * (self.number as u32 - 1)
+    }
+
+    // `number` is checked in `new`, so the last arm is channel 7
+
+    fn ccr(&self) -> u32 {
+        match self.number {
+            1 => self.dma.ccr1.read().bits(),
+            2 => self.dma.ccr2.read().bits(),
+            3 => self.dma.ccr3.read().bits(),
+            4 => self.dma.ccr4.read().bits(),
+            5 => self.dma.ccr5.read().bits(),
+            6 => self.dma.ccr6.read().bits(),
+            _ => self.dma.ccr7.read().bits(),
+        }
+    }
+
+    fn set_ccr(&self, value: u32) {
+        match self.number {
+            1 => self.dma.ccr1.write(|w| unsafe { w.bits(value) }),
+            2 => self.dma.ccr2.write(|w| unsafe { w.bits(value) }),
+            3 => self.dma.ccr3.write(|w| unsafe { w.bits(value) }),
+            4 => self.dma.ccr4.write(|w| unsafe { w.bits(value) }),
+            5 => self.dma.ccr5.write(|w| unsafe { w.bits(value) }),
+            6 => self.dma.ccr6.write(|w| unsafe { w.bits(value) }),
+            _ => self.dma.ccr7.write(|w| unsafe { w.bits(value) }),
+        }
+    }
+
+    fn set_cndtr(&self, value: u32) {
+        match self.number {
+            1 => self.dma.cndtr1.write(|w| unsafe { w.bits(value) }),
+            2 => self.dma.cndtr2.write(|w| unsafe { w.bits(value) }),
+            3 => self.dma.cndtr3.write(|w| unsafe { w.bits(value) }),
+            4 => self.dma.cndtr4.write(|w| unsafe { w.bits(value) }),
+            5 => self.dma.cndtr5.write(|w| unsafe { w.bits(value) }),
+            6 => self.dma.cndtr6.write(|w| unsafe { w.bits(value) }),
+            _ => self.dma.cndtr7.write(|w| unsafe { w.bits(value) }),
+        }
+    }
+
+    fn set_cpar(&self, value: u32) {
+        match self.number {
+            1 => self.dma.cpar1.write(|w| unsafe { w.bits(value) }),
+            2 => self.dma.cpar2.write(|w| unsafe { w.bits(value) }),
+            3 => self.dma.cpar3.write(|w| unsafe { w.bits(value) }),
+            4 => self.dma.cpar4.write(|w| unsafe { w.bits(value) }),
+            5 => self.dma.cpar5.write(|w| unsafe { w.bits(value) }),
+            6 => self.dma.cpar6.write(|w| unsafe { w.bits(value) }),
+            _ => self.dma.cpar7.write(|w| unsafe { w.bits(value) }),
+        }
+    }
+
+    fn set_cmar(&self, value: u32) {
+        match self.number {
+            1 => self.dma.cmar1.write(|w| unsafe { w.bits(value) }),
+            2 => self.dma.cmar2.write(|w| unsafe { w.bits(value) }),
+            3 => self.dma.cmar3.write(|w| unsafe { w.bits(value) }),
+            4 => self.dma.cmar4.write(|w| unsafe { w.bits(value) }),
+            5 => self.dma.cmar5.write(|w| unsafe { w.bits(value) }),
+            6 => self.dma.cmar6.write(|w| unsafe { w.bits(value) }),
+            _ => self.dma.cmar7.write(|w| unsafe { w.bits(value) }),
+        }
+    }
+}
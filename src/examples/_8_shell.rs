@@ -0,0 +1,135 @@
+//! Command shell on a buffered serial interface
+//!
+//! ```
+//! 
+//! #![feature(const_fn)]
+//! #![feature(used)]
+//! #![no_std]
+//! 
+//! // version = "0.2.0"
+//! extern crate cortex_m_rt;
+//! 
+//! // version = "0.1.0"
+//! #[macro_use]
+//! extern crate cortex_m_rtfm as rtfm;
+//! 
+//! extern crate bluepill;
+//! 
+//! use bluepill::led::{self, LEDS};
+//! use bluepill::serial::{BufferedSerial, Config, Serial};
+//! use bluepill::shell::{self, Command, Shell};
+//! use bluepill::stm32f103xx::interrupt::Usart3;
+//! use bluepill::stm32f103xx;
+//! use rtfm::{Resource, P0, P1, T0, T1, TMax, C1};
+//! use core::cell::RefCell;
+//! use core::fmt::Write;
+//! 
+//! // CONFIGURATION
+//! pub const BAUD_RATE: u32 = 115_200; // bits per second
+//! 
+//! static COMMANDS: [Command; 2] = [
+//!     Command { name: "echo", help: "echo <args> - print the arguments", run: echo },
+//!     Command { name: "led", help: "led on|off - switch the user LED", run: led },
+//! ];
+//! 
+//! fn echo(args: &[&str], out: &mut Write) {
+//!     for arg in &args[1..] {
+//!         write!(out, "{} ", arg).ok();
+//!     }
+//!     out.write_str("\r\n").ok();
+//! }
+//! 
+//! fn led(args: &[&str], out: &mut Write) {
+//!     match args.get(1) {
+//!         Some(&"on") => LEDS[0].on(),
+//!         Some(&"off") => LEDS[0].off(),
+//!         _ => { out.write_str("usage: led on|off\r\n").ok(); },
+//!     }
+//! }
+//! 
+//! struct Console {
+//!     shell: RefCell<Shell>,
+//!     serial: RefCell<BufferedSerial>,
+//! }
+//! 
+//! impl Console {
+//!     const fn new() -> Self {
+//!         Console {shell: RefCell::new(Shell::new(&COMMANDS)),
+//!                  serial: RefCell::new(BufferedSerial::new())}
+//!     }
+//! }
+//! 
+//! static CONSOLE: Resource<Console, C1> = Resource::new(Console::new());
+//! 
+//! // RESOURCES
+//! peripherals!(stm32f103xx, {
+//!     GPIOB: Peripheral {
+//!         register_block: Gpiob,
+//!         ceiling: C0,
+//!     },
+//!     GPIOC: Peripheral {
+//!         register_block: Gpioc,
+//!         ceiling: C0,
+//!     },
+//!     RCC: Peripheral {
+//!         register_block: Rcc,
+//!         ceiling: C0,
+//!     },
+//!     USART3: Peripheral {
+//!         register_block: Usart3,
+//!         ceiling: C1,
+//!     },
+//! });
+//! 
+//! // INITIALIZATION PHASE
+//! fn init(ref priority: P0, threshold: &TMax) {
+//!     let gpiob = GPIOB.access(priority, threshold);
+//!     let gpioc = GPIOC.access(priority, threshold);
+//!     let rcc = RCC.access(priority, threshold);
+//!     let usart3 = USART3.access(priority, threshold);
+//!     let console = CONSOLE.access(priority, threshold);
+//! 
+//!     let serial = Serial{usart: &**usart3};
+//! 
+//!     led::init(&gpioc, &rcc);
+//!     serial.init(&gpiob, &rcc, Config::new(BAUD_RATE)).unwrap();
+//! 
+//!     // print the first prompt
+//!     let mut buffered = console.serial.borrow_mut();
+//!     console.shell.borrow().prompt(&mut buffered.writer(serial));
+//! }
+//! 
+//! // IDLE LOOP
+//! fn idle(_priority: P0, _threshold: T0) -> ! {
+//!     // Sleep
+//!     loop {
+//!         rtfm::wfi();
+//!     }
+//! }
+//! 
+//! // TASKS
+//! tasks!(stm32f103xx, {
+//!     console: Task {
+//!         interrupt: Usart3,
+//!         priority: P1,
+//!         enabled: true,
+//!     },
+//! });
+//! 
+//! // Run the received commands
+//! fn console(mut _task: Usart3, ref priority: P1, ref threshold: T1) {
+//!     let console = CONSOLE.access(priority, threshold);
+//!     let mut shell = console.shell.borrow_mut();
+//!     let mut buffered = console.serial.borrow_mut();
+//! 
+//!     let usart3 = USART3.access(priority, threshold);
+//!     let serial = Serial{usart: &**usart3};
+//! 
+//!     // move received bytes into the ring buffer and keep transmitting
+//!     buffered.on_interrupt(serial);
+//! 
+//!     // echo, edit and run commands, output is sent from the TXE interrupt
+//!     shell::poll(&mut shell, &mut buffered, serial);
+//! }
+//! ```
+// Auto-generated. Do not modify.
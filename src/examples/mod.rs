@@ -8,3 +8,4 @@ pub mod _4_buffered_serial;
 pub mod _5_pwm;
 pub mod _6_stepper_tasks;
 pub mod _7_stepper;
+pub mod _8_shell;
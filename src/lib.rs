@@ -19,10 +19,14 @@
 //#![deny(warnings)]
 #![no_std]
 #![feature(associated_type_defaults)]
+#![feature(const_fn)]
 
 extern crate cast;
+extern crate cortex_m;
 pub extern crate stm32f103xx;
 extern crate hal;
+extern crate heapless;
+extern crate bluepill_protocol;
 
 // For documentation only
 pub mod examples;
@@ -32,6 +36,18 @@ pub mod led;
 pub mod timer;
 pub mod clock;
 pub mod pin;
+#[macro_use]
 pub mod serial;
 
 pub mod frequency;
+pub mod delay;
+pub mod soft_timer;
+pub mod counter;
+pub mod dma;
+pub mod rs485;
+pub mod lin;
+pub mod shell;
+#[cfg(feature = "rpc")]
+pub mod rpc;
+
+pub use bluepill_protocol::{cobs, slip};
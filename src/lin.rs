@@ -0,0 +1,66 @@
+//! LIN bus on a USART
+//!
+//! Runs on a USART switched to LIN mode with `Serial::enable_lin`. The frame
+//! layer doesn't depend on the hardware, it lives in the `bluepill-protocol`
+//! crate and is re-exported here.
+//!
+//! Both nodes feed every received byte to a `Parser` and call `on_break` when
+//! the USART reports `Event::LinBreak`. The break itself also shows up as a
+//! framing error from `Serial::read`, which should be ignored.
+
+pub use bluepill_protocol::lin::{checksum, protected_id, Checksum, Error, Frame, Parser,
+                                 Received, Result, MAX_DATA, SYNC};
+
+use serial::{Event, Serial};
+
+/// Sends the data bytes and the checksum of a frame, blocking
+///
+/// Used by the master to publish and by a slave to answer a header
+pub fn write_response(serial: Serial, model: Checksum, id: u8, data: &[u8]) -> Result<()> {
+    if data.len() > MAX_DATA {
+        return Err(Error::Length);
+    }
+
+    for &byte in data {
+        while serial.write(byte).is_err() {}
+    }
+    let checksum = checksum(model, protected_id(id), data);
+    while serial.write(checksum).is_err() {}
+
+    Ok(())
+}
+
+/// LIN master
+pub struct Master<'a> {
+    /// serial interface in LIN mode
+    pub serial: Serial<'a>,
+    /// checksum model of the cluster
+    pub checksum: Checksum,
+}
+
+impl<'a> Master<'a> {
+    /// returns a new master
+    pub fn new(serial: Serial<'a>, checksum: Checksum) -> Master<'a> {
+        Master { serial, checksum }
+    }
+
+    /// Sends a frame header: break, sync byte and protected identifier
+    pub fn send_header(&self, id: u8) {
+        // wait for the previous byte so the break isn't sent in the middle of it
+        while !self.serial.is_set(Event::Txe) {}
+        self.serial.send_break();
+
+        while self.serial.write(SYNC).is_err() {}
+        while self.serial.write(protected_id(id)).is_err() {}
+    }
+
+    /// Sends a complete frame, header and response
+    pub fn publish(&self, id: u8, data: &[u8]) -> Result<()> {
+        if data.len() > MAX_DATA {
+            return Err(Error::Length);
+        }
+
+        self.send_header(id);
+        write_response(self.serial, self.checksum, id, data)
+    }
+}
@@ -1,8 +1,25 @@
 //! GPIO pin
-use stm32f103xx::{GPIOA, GPIOB, GPIOC, GPIOD, gpioa, Rcc, rcc, adc1, tim2, TIM2, TIM3, TIM4, TIM5};
+use cortex_m::asm;
+use stm32f103xx::{GPIOA, GPIOB, GPIOC, GPIOD, ADC1, ADC2, gpioa, Rcc, rcc, adc1, tim2};
 pub use hal::pin::Pin as halPin;
 pub use hal::pin::{State, Mode};
 use ::frequency;
+use ::dma;
+use ::timer::{self, Burst, Channel, OutputCompare, Timer};
+
+// ADC CR2 bits
+const ADON: u32 = 1 << 0;
+const CAL: u32 = 1 << 2;
+// EXTSEL = SWSTART
+const EXTSEL_SWSTART: u32 = 0b111 << 17;
+const EXTTRIG: u32 = 1 << 20;
+const SWSTART: u32 = 1 << 22;
+// ADC SR bits
+const EOC: u32 = 1 << 1;
+// 239.5 cycles, the longest sample time, suits high impedance sources
+const SAMPLE_TIME: u32 = 0b111;
+// RCC CFGR ADCPRE field
+const ADCPRE_SHIFT: u32 = 14;
 
 /// GPIO pin
 pub struct Pin<'a>{
@@ -32,15 +49,7 @@ impl<'a> Pin<'a>{
 
     /// Initializes the Pin
     pub fn init(&self, rcc: &Rcc, mode: Mode) {
-        // Power up peripherals
-        // check which memory block this port is pointing to
-        match &*self.port as *const _{
-            x if x == GPIOA.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopaen().enabled()),
-            x if x == GPIOB.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopben().enabled()),
-            x if x == GPIOC.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopcen().enabled()),
-            x if x == GPIOD.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopden().enabled()),
-            _ => {},
-        }
+        self.power_up(rcc);
 
         // Configure pin to input/output
         // still need to handle analog input mode, and possibly setting output frequency
@@ -85,7 +94,19 @@ impl<'a> Pin<'a>{
                                                     .cnf15().alt_push()),
                     _ => {},
                 },
-            Mode::ANALOG_INPUT => {},
+            Mode::ANALOG_INPUT => {
+                // MODE and CNF both zero is analog input
+                let shift = (self.pin as u32 % 8) * 4;
+                match self.pin {
+                    0...7 => self.port.crl.modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << shift)) }),
+                    8...15 => self.port.crh.modify(|r, w| unsafe { w.bits(r.bits() & !(0xF << shift)) }),
+                    _ => {},
+                }
+
+                if let Some(adc) = self.adc {
+                    self.adc_power_up(rcc, adc);
+                }
+            },
             Mode::OUTPUT =>
                 match self.pin {
                     0 => self.port.crl.modify(|_,w| w.mode0().output()
@@ -124,13 +145,7 @@ impl<'a> Pin<'a>{
                 },
             Mode::PWM_OUTPUT => {
                 if let Some(timer) = self.timer {
-                    match &*timer as *const _{
-                        x if x == TIM2.get() as *const _ => rcc.apb1enr.modify(|_, w| w.tim2en().enabled()),
-                        x if x == TIM3.get() as *const _ => rcc.apb1enr.modify(|_, w| w.tim3en().enabled()),
-                        x if x == TIM4.get() as *const _ => rcc.apb1enr.modify(|_, w| w.tim4en().enabled()),
-                        x if x == TIM5.get() as *const _ => rcc.apb1enr.modify(|_, w| w.tim5en().enabled()),
-                        _ => {},
-                    }
+                    Timer::new(timer).power_up(rcc);
 
                     // PSC = (CLOCK / FREQ) / u16::MAX
                     // ARR = ((CLOCK / FREQ) + (PSC / 2)) / PSC
@@ -151,59 +166,8 @@ impl<'a> Pin<'a>{
                     // set frequency
                     timer.arr.write(|w| w.arr().bits(arr));
 
-                    // Valid pins are PA 8, 9, 10, 11  timer 1
-                    //                PA 0, 1, 2, 3    timer 2
-                    //                PA 6, 7 PB 0, 1  timer 3
-                    //                PB 6, 7, 8, 9    timer 4            
-
-                    // ocXm = pwm1 mode
-                    // ocXpe = preload enable
-                    // ccXe = output enable
-                    // ccXp = active high
-                    match &*self.port as *const _{
-                        x if x == GPIOA.get() as *const _ => {
-                            match self.pin {
-                                0 | 6 | 8 => { timer.ccmr1_output.modify(|_,w| unsafe{ w.oc1m().bits(0b110)
-                                                                                        .oc1pe().bits(1) });
-                                               timer.ccer.modify(|_, w| unsafe{ w.cc1e().bits(1)
-                                                                                 .cc1p().bits(0) }); },
-                                1 | 7 | 9 => { timer.ccmr1_output.modify(|_,w| unsafe{ w.oc2m().bits(0b110)
-                                                                                        .oc2pe().bits(1) });
-                                               timer.ccer.modify(|_, w| unsafe{ w.cc2e().bits(1)
-                                                                                 .cc2p().bits(0) }); },
-                                2 | 10 => {    timer.ccmr2_output.modify(|_,w| unsafe{ w.oc3m().bits(0b110)
-                                                                                        .oc3pe().bits(1) });
-                                               timer.ccer.modify(|_, w| unsafe{ w.cc3e().bits(1)
-                                                                                 .cc3p().bits(0) }); },
-                                3 | 11 => {    timer.ccmr2_output.modify(|_,w| unsafe{ w.oc4m().bits(0b110)
-                                                                                        .oc4pe().bits(1) });
-                                               timer.ccer.modify(|_, w| unsafe{ w.cc4e().bits(1)
-                                                                                 .cc4p().bits(0) }); },
-                                _ => {},
-                            }
-                        },
-                        x if x == GPIOB.get() as *const _ => {
-                            match self.pin {
-                                6 => {     timer.ccmr1_output.modify(|_,w| unsafe{ w.oc1m().bits(0b110)
-                                                                                    .oc1pe().bits(1) });
-                                           timer.ccer.modify(|_, w| unsafe{ w.cc1e().bits(1)
-                                                                             .cc1p().bits(0) }); },
-                                7 => {     timer.ccmr1_output.modify(|_,w| unsafe{ w.oc2m().bits(0b110)
-                                                                                    .oc2pe().bits(1) });
-                                           timer.ccer.modify(|_, w| unsafe{ w.cc2e().bits(1)
-                                                                             .cc2p().bits(0) }); },
-                                0 | 8 => { timer.ccmr2_output.modify(|_,w| unsafe{ w.oc3m().bits(0b110)
-                                                                                    .oc3pe().bits(1) });
-                                           timer.ccer.modify(|_, w| unsafe{ w.cc3e().bits(1)
-                                                                             .cc3p().bits(0) }); },
-                                1 | 9 => { timer.ccmr2_output.modify(|_,w| unsafe{ w.oc4m().bits(0b110)
-                                                                                    .oc4pe().bits(1) });
-                                           timer.ccer.modify(|_, w| unsafe{ w.cc4e().bits(1)
-                                                                             .cc4p().bits(0) }); },
-                                _ => {},
-                            }
-                        },
-                        _ => {},
+                    if let Some(channel) = self.channel() {
+                        Timer::new(timer).set_output_compare(channel, OutputCompare::Pwm1);
                     }
 
                     // set update generation bit
@@ -216,31 +180,165 @@ impl<'a> Pin<'a>{
                                             .arpe().bits(1) });
                 }
 
+                self.alt_output();
+            },
+        };
+    }
+
+    /// Initializes the Pin as an output compare channel of its timer
+    ///
+    /// The timer period is left alone, configure it with `Timer::init`
+    pub fn init_compare(&self, rcc: &Rcc, mode: OutputCompare) {
+        self.power_up(rcc);
+
+        if let (Some(timer), Some(channel)) = (self.timer, self.channel()) {
+            let timer = Timer::new(timer);
+            timer.power_up(rcc);
+            timer.set_output_compare(channel, mode);
+        }
+
+        self.alt_output();
+    }
+
+    /// Plays back `buffer` as duty cycle values on a pin initialized with
+    /// `Mode::PWM_OUTPUT`, one value per PWM period
+    ///
+    /// Values are raw compare values, compare them against the timer ARR.
+    /// Returns `Err` if the pin has no timer channel or `dma` doesn't serve
+    /// the pin's timer
+    pub fn pwm_stream(&self, dma: &dma::Channel, buffer: &'static [u16], circular: bool) -> timer::Result<()> {
+        match (self.timer, self.channel()) {
+            (Some(timer), Some(channel)) => Timer::new(timer).stream(dma, buffer, Burst::Compare(channel), circular),
+            _ => Err(timer::Error::new()),
+        }
+    }
+
+    /// Returns the timer channel connected to this pin
+    ///
+    /// Returns `None` if the pin has no timer, see `new_pwm_out`
+    pub fn channel(&self) -> Option<Channel> {
+        if self.timer.is_none() {
+            return None;
+        }
+
+        // Valid pins are PA 8, 9, 10, 11  timer 1
+        //                PA 0, 1, 2, 3    timer 2
+        //                PA 6, 7 PB 0, 1  timer 3
+        //                PB 6, 7, 8, 9    timer 4
+        match &*self.port as *const _{
+            x if x == GPIOA.get() as *const _ => {
                 match self.pin {
-                    0 => self.port.crl.modify(|_,w| w.mode0().output50()
-                                                        .cnf0().alt_push()),
-                    1 => self.port.crl.modify(|_,w| w.mode1().output50()
-                                                        .cnf1().alt_push()),
-                    2 => self.port.crl.modify(|_,w| w.mode2().output50()
-                                                        .cnf2().alt_push()),
-                    3 => self.port.crl.modify(|_,w| w.mode3().output50()
-                                                        .cnf3().alt_push()),
-                    6 => self.port.crl.modify(|_,w| w.mode6().output50()
-                                                        .cnf6().alt_push()),
-                    7 => self.port.crl.modify(|_,w| w.mode7().output50()
-                                                        .cnf7().alt_push()),
-                    8 => self.port.crh.modify(|_,w| w.mode8().output50()
-                                                        .cnf8().alt_push()),
-                    9 => self.port.crh.modify(|_,w| w.mode9().output50()
-                                                        .cnf9().alt_push()),
-                    10 => self.port.crh.modify(|_,w| w.mode10().output50()
-                                                        .cnf10().alt_push()),
-                    11 => self.port.crh.modify(|_,w| w.mode11().output50()
-                                                        .cnf11().alt_push()),
-                    _ => {},
+                    0 | 6 | 8 => Some(Channel::C1),
+                    1 | 7 | 9 => Some(Channel::C2),
+                    2 | 10 => Some(Channel::C3),
+                    3 | 11 => Some(Channel::C4),
+                    _ => None,
+                }
+            },
+            x if x == GPIOB.get() as *const _ => {
+                match self.pin {
+                    6 => Some(Channel::C1),
+                    7 => Some(Channel::C2),
+                    0 | 8 => Some(Channel::C3),
+                    1 | 9 => Some(Channel::C4),
+                    _ => None,
                 }
             },
+            _ => None,
+        }
+    }
+
+    /// Returns the ADC input channel connected to this pin
+    ///
+    /// Returns `None` if the pin has no ADC, see `new_analog_in`
+    pub fn adc_channel(&self) -> Option<u8> {
+        if self.adc.is_none() {
+            return None;
+        }
+
+        // Valid pins are PA 0 - 7  channel 0 - 7
+        //                PB 0, 1   channel 8, 9
+        //                PC 0 - 5  channel 10 - 15
+        match &*self.port as *const _{
+            x if x == GPIOA.get() as *const _ && self.pin < 8 => Some(self.pin),
+            x if x == GPIOB.get() as *const _ && self.pin < 2 => Some(self.pin + 8),
+            x if x == GPIOC.get() as *const _ && self.pin < 6 => Some(self.pin + 10),
+            _ => None,
+        }
+    }
+
+    /// Powers up and calibrates the ADC, single conversions are started by
+    /// software
+    fn adc_power_up(&self, rcc: &Rcc, adc: &adc1::RegisterBlock) {
+        match adc as *const _ {
+            x if x == ADC1.get() as *const _ => rcc.apb2enr.modify(|_, w| w.adc1en().enabled()),
+            x if x == ADC2.get() as *const _ => rcc.apb2enr.modify(|_, w| w.adc2en().enabled()),
+            _ => {},
+        }
+
+        // the ADC clock is APB2 divided by 2, 4, 6 or 8 and must not exceed 14 MHz
+        let speeds = frequency::ClockSpeeds::get(rcc);
+        let prescaler = match speeds.apb2 {
+            0...28_000_000 => 0b00,
+            28_000_001...56_000_000 => 0b01,
+            56_000_001...84_000_000 => 0b10,
+            _ => 0b11,
         };
+        rcc.cfgr.modify(|r, w| unsafe {
+            w.bits((r.bits() & !(0b11 << ADCPRE_SHIFT)) | (prescaler << ADCPRE_SHIFT))
+        });
+
+        if adc.cr2.read().bits() & ADON == 0 {
+            adc.cr2.write(|w| unsafe { w.bits(ADON | EXTSEL_SWSTART | EXTTRIG) });
+            // wait t_STAB, 1 us, before calibrating
+            for _ in 0..speeds.sysclk / 1_000_000 {
+                asm::nop();
+            }
+            adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | CAL) });
+            while adc.cr2.read().bits() & CAL != 0 {}
+        } else {
+            // shared with another pin, make sure conversions start on SWSTART
+            adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | EXTSEL_SWSTART | EXTTRIG) });
+        }
+    }
+
+    fn power_up(&self, rcc: &Rcc) {
+        // Power up peripherals
+        // check which memory block this port is pointing to
+        match &*self.port as *const _{
+            x if x == GPIOA.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopaen().enabled()),
+            x if x == GPIOB.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopben().enabled()),
+            x if x == GPIOC.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopcen().enabled()),
+            x if x == GPIOD.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopden().enabled()),
+            _ => {},
+        }
+    }
+
+    /// Configures the pin as alternate function push pull output
+    fn alt_output(&self) {
+        match self.pin {
+            0 => self.port.crl.modify(|_,w| w.mode0().output50()
+                                                .cnf0().alt_push()),
+            1 => self.port.crl.modify(|_,w| w.mode1().output50()
+                                                .cnf1().alt_push()),
+            2 => self.port.crl.modify(|_,w| w.mode2().output50()
+                                                .cnf2().alt_push()),
+            3 => self.port.crl.modify(|_,w| w.mode3().output50()
+                                                .cnf3().alt_push()),
+            6 => self.port.crl.modify(|_,w| w.mode6().output50()
+                                                .cnf6().alt_push()),
+            7 => self.port.crl.modify(|_,w| w.mode7().output50()
+                                                .cnf7().alt_push()),
+            8 => self.port.crh.modify(|_,w| w.mode8().output50()
+                                                .cnf8().alt_push()),
+            9 => self.port.crh.modify(|_,w| w.mode9().output50()
+                                                .cnf9().alt_push()),
+            10 => self.port.crh.modify(|_,w| w.mode10().output50()
+                                                .cnf10().alt_push()),
+            11 => self.port.crh.modify(|_,w| w.mode11().output50()
+                                                .cnf11().alt_push()),
+            _ => {},
+        }
     }
 }
 
@@ -265,12 +363,35 @@ impl<'a> halPin<u16> for Pin<'a>{
         }
     }
 
+    /// Returns the result of a single ADC conversion, blocking
+    ///
+    /// Returns 0 if the pin has no ADC channel, check `adc_channel`
     fn analog_read(&self) -> u16 {
-        1
+        let (adc, channel) = match (self.adc, self.adc_channel()) {
+            (Some(adc), Some(channel)) => (adc, channel as u32),
+            _ => return 0,
+        };
+
+        if channel < 10 {
+            let shift = channel * 3;
+            adc.smpr2.modify(|r, w| unsafe { w.bits(r.bits() | SAMPLE_TIME << shift) });
+        } else {
+            let shift = (channel - 10) * 3;
+            adc.smpr1.modify(|r, w| unsafe { w.bits(r.bits() | SAMPLE_TIME << shift) });
+        }
+
+        // a regular sequence of one conversion, L = 0
+        adc.sqr1.write(|w| unsafe { w.bits(0) });
+        adc.sqr3.write(|w| unsafe { w.bits(channel) });
+        adc.cr2.modify(|r, w| unsafe { w.bits(r.bits() | SWSTART) });
+        while adc.sr.read().bits() & EOC == 0 {}
+
+        // reading DR clears EOC
+        adc.dr.read().bits() as u16
     }
 
     fn pwm_write(&self, duty_cycle: u8){
-        if let Some(timer) = self.timer {
+        if let (Some(timer), Some(channel)) = (self.timer, self.channel()) {
             let value = if duty_cycle == 0 {
                 0
             } else {
@@ -278,7 +399,7 @@ impl<'a> halPin<u16> for Pin<'a>{
                 let duty_cycle = (duty_cycle as u32 * 100) / 255;
                 ((arr * duty_cycle) / 100) as u16
             };
-            timer.ccr4.write(|w| unsafe{ w.ccr4().bits(value) });;
+            Timer::new(timer).set_compare(channel, value);
         }
     }
 }
\ No newline at end of file
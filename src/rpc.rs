@@ -0,0 +1,149 @@
+//! Binary RPC for host controlled testing
+//!
+//! Serves the protocol of `bluepill_protocol::rpc` on the board peripherals,
+//! see that module for the wire format and the opcodes. Enabled with the
+//! `rpc` cargo feature. Pins and timers are addressed by their index in the
+//! tables handed to `Peripherals`.
+//!
+//! `PWM_WRITE` answers `Error::Opcode` for pins without a timer channel,
+//! `ANALOG_READ` for pins without an ADC channel.
+//!
+//! ``` ignore
+//! static SERVER: Resource<RefCell<Server<[u8; 34]>>, C1> =
+//!     Resource::new(RefCell::new(Server::new([0; 34])));
+//!
+//! // USART interrupt
+//! buffered.on_interrupt(serial);
+//! let mut board = Peripherals { pins: &pins, timers: &timers };
+//! rpc::poll(&mut server, &mut buffered, serial, &mut board);
+//! ```
+
+use pin::{halPin, Pin, State};
+use serial::{BufferedSerial, Serial};
+use timer::{halTimer, Channel, Timer};
+
+pub use bluepill_protocol::rpc::{crc16, Board, Error, Result, Server, Transport, ANALOG_READ,
+                                 DIGITAL_READ, DIGITAL_WRITE, MAX_FRAME, MAX_PAYLOAD, PING, PWM_WRITE,
+                                 TIMER_COMPARE, TIMER_COUNT, TIMER_RUN};
+
+impl<'a> Transport for Serial<'a> {
+    /// Sends a byte, blocking
+    fn send(&mut self, byte: u8) {
+        while self.write(byte).is_err() {}
+    }
+}
+
+// Queues the response on a buffered driver
+struct Queued<'a, 'b> {
+    buffered: &'b mut BufferedSerial,
+    serial: Serial<'a>,
+}
+
+impl<'a, 'b> Transport for Queued<'a, 'b> {
+    fn send(&mut self, byte: u8) {
+        // `poll` made room for the whole response
+        self.buffered.write_all(self.serial, &[byte]);
+    }
+}
+
+/// Feeds the bytes received by a buffered driver to `server`, responses are
+/// queued on the same driver
+///
+/// Call this from the USART interrupt after `BufferedSerial::on_interrupt`.
+/// Bytes are left in the RX ring buffer while the TX ring buffer can't take
+/// `MAX_FRAME` more bytes, the TXE interrupts that drain it call `poll`
+/// again.
+pub fn poll<A, B>(server: &mut Server<A>, buffered: &mut BufferedSerial, serial: Serial, board: &mut B)
+where
+    A: AsMut<[u8]> + AsRef<[u8]>,
+    B: Board,
+{
+    while buffered.room() >= MAX_FRAME {
+        let byte = match buffered.read(serial) {
+            Some(byte) => byte,
+            None => return,
+        };
+        let mut queued = Queued { buffered: &mut *buffered, serial };
+        server.feed(byte, board, &mut queued);
+    }
+}
+
+/// The board peripherals made available to the host
+pub struct Peripherals<'a> {
+    /// pins, addressed by index
+    pub pins: &'a [Pin<'a>],
+    /// timers, addressed by index
+    pub timers: &'a [Timer<'a>],
+}
+
+impl<'a> Peripherals<'a> {
+    fn pin(&self, pin: u8) -> Result<&Pin<'a>> {
+        self.pins.get(pin as usize).ok_or(Error::Index)
+    }
+
+    fn timer(&self, timer: u8) -> Result<&Timer<'a>> {
+        self.timers.get(timer as usize).ok_or(Error::Index)
+    }
+}
+
+impl<'a> Board for Peripherals<'a> {
+    fn digital_write(&mut self, pin: u8, high: bool) -> Result<()> {
+        let pin = self.pin(pin)?;
+        if high {
+            pin.on();
+        } else {
+            pin.off();
+        }
+        Ok(())
+    }
+
+    fn digital_read(&mut self, pin: u8) -> Result<bool> {
+        match self.pin(pin)?.digital_read() {
+            State::HIGH => Ok(true),
+            State::LOW => Ok(false),
+        }
+    }
+
+    fn pwm_write(&mut self, pin: u8, duty_cycle: u8) -> Result<()> {
+        let pin = self.pin(pin)?;
+        if pin.channel().is_none() {
+            return Err(Error::Opcode);
+        }
+        pin.pwm_write(duty_cycle);
+        Ok(())
+    }
+
+    fn analog_read(&mut self, pin: u8) -> Result<u16> {
+        let pin = self.pin(pin)?;
+        if pin.adc_channel().is_none() {
+            return Err(Error::Opcode);
+        }
+        Ok(pin.analog_read())
+    }
+
+    fn timer_count(&mut self, timer: u8) -> Result<u16> {
+        Ok(self.timer(timer)?.count())
+    }
+
+    fn timer_compare(&mut self, timer: u8, channel: u8, value: u16) -> Result<()> {
+        let channel = match channel {
+            1 => Channel::C1,
+            2 => Channel::C2,
+            3 => Channel::C3,
+            4 => Channel::C4,
+            _ => return Err(Error::Argument),
+        };
+        self.timer(timer)?.set_compare(channel, value);
+        Ok(())
+    }
+
+    fn timer_run(&mut self, timer: u8, running: bool) -> Result<()> {
+        let timer = self.timer(timer)?;
+        if running {
+            timer.resume();
+        } else {
+            timer.pause();
+        }
+        Ok(())
+    }
+}
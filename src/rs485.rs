@@ -0,0 +1,65 @@
+//! RS-485 transceiver
+//!
+//! Drives the DE/RE pin of a half-duplex transceiver (MAX485 and similar,
+//! with DE and RE tied together). The driver is enabled before the first
+//! byte and released only after the TC flag, so the stop bit of the last byte
+//! isn't cut off.
+
+use stm32f103xx::Rcc;
+
+use pin::{halPin, Mode, Pin};
+use serial::{BufferedSerial, Event, Serial};
+
+/// RS-485 link
+pub struct Rs485<'a> {
+    /// serial interface connected to DI/RO
+    pub serial: Serial<'a>,
+    /// driver enable pin, active high
+    pub de: &'a Pin<'a>,
+}
+
+impl<'a> Rs485<'a> {
+    /// returns a new RS-485 link
+    pub fn new(serial: Serial<'a>, de: &'a Pin<'a>) -> Rs485<'a> {
+        Rs485 { serial, de }
+    }
+
+    /// Configures the driver enable pin and releases the bus
+    ///
+    /// The serial interface has to be initialized separately
+    pub fn init(&self, rcc: &Rcc) {
+        self.de.init(rcc, Mode::OUTPUT);
+        self.de.off();
+    }
+
+    /// Sends `bytes`, blocking until the last one has left the shift register
+    pub fn write_all(&self, bytes: &[u8]) {
+        self.de.on();
+
+        for &byte in bytes {
+            while self.serial.write(byte).is_err() {}
+        }
+
+        while !self.serial.is_set(Event::Tc) {}
+        self.de.off();
+    }
+
+    /// Queues `bytes` on a buffered driver, enabling the transceiver first
+    ///
+    /// Returns the number of bytes queued. `on_interrupt` releases the bus
+    /// once they have been sent.
+    pub fn send(&self, buffered: &mut BufferedSerial, bytes: &[u8]) -> usize {
+        self.de.on();
+        buffered.write_all(self.serial, bytes)
+    }
+
+    /// Services the buffered driver, call this from the USART interrupt
+    /// instead of `BufferedSerial::on_interrupt`
+    pub fn on_interrupt(&self, buffered: &mut BufferedSerial) {
+        buffered.on_interrupt(self.serial);
+
+        if buffered.is_flushed(self.serial) {
+            self.de.off();
+        }
+    }
+}
@@ -2,19 +2,246 @@
 //!
 //! - TX - PA9
 //! - RX - PA10
+//! - CTS - PA11 (hardware flow control only)
+//! - RTS - PA12 (hardware flow control only)
+//!
+//! `Serial` implements `core::fmt::Write` by blocking until every byte is in
+//! the TX buffer, use it with the `sprint!` and `sprintln!` macros:
+//!
+//! ``` ignore
+//! sprintln!(serial, "adc: {}", value);
+//! ```
 
+use core::fmt;
 use core::ptr;
+use core::u16;
+use core::sync::atomic::{AtomicBool, Ordering};
 
+use heapless::RingBuffer;
 use stm32f103xx::{gpioa, GPIOA, GPIOB, Rcc, usart1, USART1, USART2, USART3};
 
+use dma;
 use frequency;
+use timer::{self, halTimer, Edge, Timer};
+
+/// Capacity of the buffered driver RX ring buffer
+pub const RX_BUFFER_SIZE: usize = 64;
+/// Capacity of the buffered driver TX ring buffer, large enough for a line of
+/// `shell` output
+pub const TX_BUFFER_SIZE: usize = 128;
+
+/// XON control character, resume transmission
+pub const XON: u8 = 0x11;
+/// XOFF control character, pause transmission
+pub const XOFF: u8 = 0x13;
+/// RX ring buffer level at which the buffered driver sends XOFF
+pub const XOFF_WATERMARK: usize = RX_BUFFER_SIZE * 3 / 4;
+/// RX ring buffer level at which the buffered driver sends XON again
+pub const XON_WATERMARK: usize = RX_BUFFER_SIZE / 4;
+
+/// Standard baud rates preferred by `autobaud`
+pub const STANDARD_BAUD_RATES: [u32; 12] = [
+    1_200, 2_400, 4_800, 9_600, 19_200, 38_400, 57_600, 115_200, 230_400, 460_800, 921_600,
+    1_000_000,
+];
+
+/// Largest baud rate error accepted, in percent
+pub const BAUD_TOLERANCE: f32 = 2.0;
+
+// Overrun seen by `Serial::read9` and not reported yet, per USART
+static OVERRUN: [AtomicBool; 3] = [AtomicBool::new(false),
+                                   AtomicBool::new(false),
+                                   AtomicBool::new(false)];
 
 /// Specialized `Result` type
 pub type Result<T> = ::core::result::Result<T, Error>;
 
 /// An error
-pub struct Error {
-    _0: (),
+#[derive(Debug)]
+pub enum Error {
+    /// the operation can't complete yet, the TX buffer is full or the RX
+    /// buffer is empty
+    WouldBlock,
+    /// a byte arrived before the previous one was read, data was lost
+    ///
+    /// Reported by the read after the one that returned the last byte
+    /// received before the overrun
+    Overrun,
+    /// no stop bit where one was expected
+    Framing,
+    /// noise detected on the line
+    Noise,
+    /// parity check failed
+    Parity,
+    /// the baud rate can't be generated within `BAUD_TOLERANCE`
+    BaudRate,
+    /// the frame format isn't supported, 9 data bits can't have parity
+    FrameFormat,
+    /// the DMA channel doesn't serve this USART
+    DmaChannel,
+    /// the buffer is too long for a single DMA transfer
+    BufferLength,
+}
+
+/// Number of data bits
+#[derive(Clone, Copy, PartialEq)]
+pub enum DataBits {
+    /// 8 data bits
+    Eight,
+    /// 9 data bits
+    Nine,
+}
+
+/// Parity bit
+#[derive(Clone, Copy, PartialEq)]
+pub enum Parity {
+    /// no parity bit
+    None,
+    /// even parity
+    Even,
+    /// odd parity
+    Odd,
+}
+
+/// Number of stop bits
+#[derive(Clone, Copy)]
+pub enum StopBits {
+    /// 0.5 stop bits
+    Half = 0b01,
+    /// 1 stop bit
+    One = 0b00,
+    /// 1.5 stop bits
+    OneAndHalf = 0b11,
+    /// 2 stop bits
+    Two = 0b10,
+}
+
+/// Flow control
+#[derive(Clone, Copy, PartialEq)]
+pub enum FlowControl {
+    /// no flow control
+    None,
+    /// hardware RTS/CTS flow control
+    RtsCts,
+}
+
+/// Direction of a half-duplex link
+#[derive(Clone, Copy, PartialEq)]
+pub enum Direction {
+    /// drive the line
+    Transmit,
+    /// listen to the line
+    Receive,
+}
+
+/// Clock polarity in synchronous mode
+#[derive(Clone, Copy)]
+pub enum Polarity {
+    /// clock is low when idle
+    IdleLow = 0,
+    /// clock is high when idle
+    IdleHigh = 1,
+}
+
+/// Clock phase in synchronous mode
+#[derive(Clone, Copy)]
+pub enum Phase {
+    /// data is captured on the first clock transition
+    CaptureOnFirstTransition = 0,
+    /// data is captured on the second clock transition
+    CaptureOnSecondTransition = 1,
+}
+
+/// Clock output configuration for synchronous mode
+#[derive(Clone, Copy)]
+pub struct ClockConfig {
+    /// clock polarity
+    pub polarity: Polarity,
+    /// clock phase
+    pub phase: Phase,
+    /// output a clock pulse for the last data bit too
+    pub last_bit_clock: bool,
+}
+
+/// Wake-up method of the mute mode
+#[derive(Clone, Copy)]
+pub enum Wakeup {
+    /// leave mute mode when the line goes idle
+    IdleLine,
+    /// leave mute mode on an address byte (MSB set) whose low 4 bits match
+    /// the node address, other address bytes mute the receiver again
+    AddressMark(u8),
+}
+
+/// Sync character sent by the remote end for baud rate detection
+#[derive(Clone, Copy)]
+pub enum SyncChar {
+    /// 0x55, falling edges 2 bits apart
+    X55,
+    /// 0x7F, falling edges 8 bits apart
+    X7F,
+}
+
+/// Serial configuration
+#[derive(Clone, Copy)]
+pub struct Config {
+    /// bits per second
+    pub baud_rate: u32,
+    /// data bits, not counting the parity bit
+    pub data_bits: DataBits,
+    /// parity
+    pub parity: Parity,
+    /// stop bits
+    pub stop_bits: StopBits,
+    /// flow control
+    pub flow_control: FlowControl,
+}
+
+impl Config {
+    /// returns an 8N1 configuration with a baud rate of `baud_rate` bits per
+    /// second
+    pub fn new(baud_rate: u32) -> Config {
+        Config {
+            baud_rate,
+            data_bits: DataBits::Eight,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+            flow_control: FlowControl::None,
+        }
+    }
+}
+
+/// Baud rate generated by the USART
+#[derive(Clone, Copy, Debug)]
+pub struct Baud {
+    /// actual baud rate in bits per second
+    pub rate: u32,
+    /// difference from the requested baud rate, in percent
+    pub error: f32,
+}
+
+/// Interrupt events
+#[derive(Clone, Copy)]
+pub enum Event {
+    /// RX buffer not empty
+    Rxne,
+    /// TX buffer empty
+    Txe,
+    /// transmission complete
+    Tc,
+    /// idle line detected
+    Idle,
+    /// LIN break detected
+    LinBreak,
+}
+
+/// LIN break detection length
+#[derive(Clone, Copy)]
+pub enum BreakLength {
+    /// 10 bit break detection
+    Bits10 = 0,
+    /// 11 bit break detection
+    Bits11 = 1,
 }
 
 /// Serial interface
@@ -22,6 +249,7 @@ pub struct Error {
 /// # Interrupts
 ///
 /// - `Usart1Exti25` - RXNE (RX buffer not empty)
+/// - `Usart1Exti25` - TXE and TC, when enabled with `listen`
 #[derive(Clone, Copy)]
 pub struct Serial<'a>{
     /// attached usart
@@ -29,9 +257,12 @@ pub struct Serial<'a>{
 }
 
 impl<'a> Serial<'a> {
-    /// Initializes the serial interface with a baud rate of `baut_rate` bits
-    /// per second
-    pub fn init(self, port: &'a gpioa::RegisterBlock, rcc: &Rcc, baud_rate: u32) {
+    /// Initializes the serial interface with the baud rate and frame format
+    /// in `config`
+    ///
+    /// Returns the baud rate actually generated, or `Err` if it is off by more
+    /// than `BAUD_TOLERANCE` or the frame format isn't supported
+    pub fn init(self, port: &'a gpioa::RegisterBlock, rcc: &Rcc, config: Config) -> Result<Baud> {
         match &*port as *const _{
             x if x == GPIOA.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopaen().enabled()),
             x if x == GPIOB.get() as *const _ => rcc.apb2enr.modify(|_, w| w.iopben().enabled()),
@@ -67,81 +298,994 @@ impl<'a> Serial<'a> {
             _ => {},
         }
 
-        // 1 stop bits
+        // clear the stop bits, set_format fills them in
         self.usart.cr2.write(|w| unsafe { w.stop().bits(0b00) });
 
-        // Disable hardware flow control
+        // CTS is a floating input, RTS an alternate function output
+        //
+        // - USART1 - CTS PA11, RTS PA12
+        // - USART2 - CTS PA0, RTS PA1
+        // - USART3 - CTS PB13, RTS PB14
+        let flow_control = if config.flow_control == FlowControl::RtsCts {
+            match &*self.usart as *const _{
+                x if x == USART1.get() as *const _ => {
+                    port.crh.modify(|_, w| w.mode11().input()
+                                            .cnf11().open()
+                                            .mode12().output50()
+                                            .cnf12().alt_push());
+                },
+                x if x == USART2.get() as *const _ => {
+                    port.crl.modify(|_, w| w.mode0().input()
+                                            .cnf0().open()
+                                            .mode1().output50()
+                                            .cnf1().alt_push());
+                },
+                x if x == USART3.get() as *const _ => {
+                    port.crh.modify(|_, w| w.mode13().input()
+                                            .cnf13().open()
+                                            .mode14().output50()
+                                            .cnf14().alt_push());
+                },
+                _ => {},
+            }
+            1
+        } else {
+            0
+        };
+
+        // Configure hardware flow control
         self.usart
             .cr3
-            .write(|w| unsafe { w.rtse().bits(0).ctse().bits(0) });
+            .write(|w| unsafe { w.rtse().bits(flow_control).ctse().bits(flow_control) });
 
-        let speeds = frequency::ClockSpeeds::get(rcc);
+        let baud = self.set_baud_rate(rcc, config.baud_rate)?;
 
-        // set baud rate
-        let brr = (speeds.apb2 / baud_rate) as u16;
-        let fraction = (brr & 0b1111) as u8;
-        let mantissa = brr >> 4;
-        self.usart
-            .brr
-            .write(
-                |w| unsafe {
-                    w.div_fraction()
-                        .bits(fraction)
-                        .div_mantissa()
-                        .bits(mantissa)
-                },
-            );
-        
-        // enable peripheral, transmitter, receiver
+        // enable transmitter, receiver
         // enable RXNE event
         self.usart
             .cr1
             .write(
                 |w| unsafe {
-                    w.ue()
-                        .bits(1)
-                        .re()
+                    w.re()
                         .bits(1)
                         .te()
                         .bits(1)
-                        .pce()
-                        .bits(0)
-                        .m()
-                        .bits(0)
                         .rxneie()
                         .bits(1)
                 },
             );
+
+        self.set_format(config)?;
+
+        // enable peripheral
+        self.usart.cr1.modify(|_, w| unsafe { w.ue().bits(1) });
+
+        Ok(baud)
+    }
+
+    /// Initializes the serial interface in single wire half-duplex mode
+    ///
+    /// Only the TX pin is used, it is configured as open drain so it needs an
+    /// external pull-up. The interface starts in the `Receive` direction.
+    ///
+    /// Returns the baud rate actually generated, or `Err` if it is off by more
+    /// than `BAUD_TOLERANCE` or the frame format isn't supported
+    pub fn init_half_duplex(self, port: &'a gpioa::RegisterBlock, rcc: &Rcc, config: Config) -> Result<Baud> {
+        let baud = self.init(port, rcc, config)?;
+
+        self.usart.cr1.modify(|_, w| unsafe { w.ue().bits(0) });
+
+        match &*self.usart as *const _{
+            x if x == USART1.get() as *const _ => {
+                port.crh.modify(|_, w| w.mode9().output50()
+                                        .cnf9().alt_open());
+            },
+            x if x == USART2.get() as *const _ => {
+                port.crl.modify(|_, w| w.mode2().output50()
+                                        .cnf2().alt_open());
+            },
+            x if x == USART3.get() as *const _ => {
+                port.crh.modify(|_, w| w.mode10().output50()
+                                        .cnf10().alt_open());
+            },
+            _ => {},
+        }
+
+        // hdsel = half-duplex selection, RX is internally tied to TX
+        self.usart.cr3.modify(|_, w| unsafe { w.hdsel().bits(1) });
+        self.usart.cr1.modify(|_, w| unsafe { w.ue().bits(1) });
+
+        self.set_direction(Direction::Receive);
+        Ok(baud)
+    }
+
+    /// Initializes the serial interface as a synchronous master, with the
+    /// clock on the CK pin
+    ///
+    /// - CK - PA8 (USART1), PA4 (USART2), PB12 (USART3)
+    ///
+    /// Data is sent LSB first, use `transfer` to clock bytes in and out.
+    ///
+    /// Returns the baud rate actually generated, or `Err` if it is off by more
+    /// than `BAUD_TOLERANCE` or the frame format isn't supported
+    pub fn init_synchronous(self, port: &'a gpioa::RegisterBlock, rcc: &Rcc, config: Config, clock: ClockConfig) -> Result<Baud> {
+        let baud = self.init(port, rcc, config)?;
+
+        // CPOL, CPHA and LBCL can't be written while the transmitter is enabled
+        self.usart.cr1.modify(|_, w| unsafe { w.ue().bits(0)
+                                                .te().bits(0) });
+
+        match &*self.usart as *const _{
+            x if x == USART1.get() as *const _ => {
+                port.crh.modify(|_, w| w.mode8().output50()
+                                        .cnf8().alt_push());
+            },
+            x if x == USART2.get() as *const _ => {
+                port.crl.modify(|_, w| w.mode4().output50()
+                                        .cnf4().alt_push());
+            },
+            x if x == USART3.get() as *const _ => {
+                port.crh.modify(|_, w| w.mode12().output50()
+                                        .cnf12().alt_push());
+            },
+            _ => {},
+        }
+
+        let lbcl = if clock.last_bit_clock { 1 } else { 0 };
+        self.usart.cr2.modify(|_, w| unsafe { w.clken().bits(1)
+                                                .cpol().bits(clock.polarity as u8)
+                                                .cpha().bits(clock.phase as u8)
+                                                .lbcl().bits(lbcl)
+                                                .linen().bits(0) });
+        // smartcard, half-duplex and IrDA must be off in synchronous mode
+        self.usart.cr3.modify(|_, w| unsafe { w.scen().bits(0)
+                                                .hdsel().bits(0)
+                                                .iren().bits(0) });
+
+        self.usart.cr1.modify(|_, w| unsafe { w.te().bits(1)
+                                                .ue().bits(1) });
+        Ok(baud)
+    }
+
+    /// Sends `byte` and returns the byte clocked in at the same time,
+    /// blocking
+    ///
+    /// Disable the RXNE interrupt while using this, otherwise the interrupt
+    /// handler races for the received byte
+    pub fn transfer(self, byte: u8) -> Result<u8> {
+        while self.write(byte).is_err() {}
+
+        loop {
+            match self.read() {
+                Err(Error::WouldBlock) => {},
+                result => return result,
+            }
+        }
+    }
+
+    /// Switches the direction of a half-duplex link
+    ///
+    /// Switching to `Receive` blocks until the last byte has been sent. The
+    /// receiver is off while transmitting so the echo of our own bytes is
+    /// ignored.
+    pub fn set_direction(self, direction: Direction) {
+        match direction {
+            Direction::Transmit => {
+                self.usart.cr1.modify(|_, w| unsafe { w.re().bits(0)
+                                                        .te().bits(1) });
+            },
+            Direction::Receive => {
+                while self.usart.sr.read().tc().bits() == 0 {}
+                self.usart.cr1.modify(|_, w| unsafe { w.re().bits(1) });
+            },
+        }
+    }
+
+    /// Selects how the receiver leaves mute mode
+    ///
+    /// Set this before calling `mute`
+    pub fn set_wakeup(self, wakeup: Wakeup) {
+        match wakeup {
+            Wakeup::IdleLine => {
+                self.usart.cr1.modify(|_, w| unsafe { w.wake().bits(0) });
+            },
+            Wakeup::AddressMark(address) => {
+                self.usart.cr2.modify(|_, w| unsafe { w.add().bits(address & 0x0F) });
+                self.usart.cr1.modify(|_, w| unsafe { w.wake().bits(1) });
+            },
+        }
+    }
+
+    /// Puts the receiver in mute mode, no bytes are received and no receive
+    /// interrupts fire until the wake-up condition is met
+    pub fn mute(self) {
+        self.usart.cr1.modify(|_, w| unsafe { w.rwu().bits(1) });
+    }
+
+    /// Leaves mute mode
+    pub fn unmute(self) {
+        self.usart.cr1.modify(|_, w| unsafe { w.rwu().bits(0) });
+    }
+
+    /// Returns `true` if the receiver is in mute mode
+    pub fn is_muted(self) -> bool {
+        self.usart.cr1.read().rwu().bits() == 1
+    }
+
+    /// Detects the baud rate of the remote end and programs it
+    ///
+    /// Blocks until the sync character arrives. The time between its first
+    /// two falling edges is measured by input capture on the RX pin, which has
+    /// to be routed to `channel` of `timer`:
+    ///
+    /// - PA10 (USART1 RX) - TIM1 channel 3
+    /// - PA3 (USART2 RX) - TIM2 channel 4
+    /// - PB11 (USART3 RX) - TIM2 channel 4, with the TIM2 full remap
+    ///
+    /// The measured rate snaps to the nearest standard baud rate when it is
+    /// within `BAUD_TOLERANCE`. Returns the baud rate chosen.
+    pub fn autobaud(self, rcc: &Rcc, timer: &Timer, channel: timer::Channel, sync: SyncChar) -> Result<Baud> {
+        let bits = match sync {
+            SyncChar::X55 => 2,
+            SyncChar::X7F => 8,
+        };
+
+        // free running counter at the full timer clock
+        timer.power_up(rcc);
+        timer.pause();
+        timer.timer.psc.write(|w| w.psc().bits(0));
+        timer.timer.arr.write(|w| w.arr().bits(0xFFFF));
+        timer.timer.egr.write(|w| unsafe { w.ug().bits(1) });
+        timer.set_input_capture(channel, Edge::Falling);
+        timer.capture(channel).ok();
+        // UG sets the update flag too
+        timer.clear_update_flag().ok();
+        timer.resume();
+
+        // The capture is polled before the update flag, so an overflow
+        // before the edge is always seen together with it. When both show up
+        // at once the captured value tells which came first: the counter
+        // wraps at most once per poll, a capture in the lower half was taken
+        // after the overflow.
+
+        // start bit, only an overflow after the capture is part of the
+        // measured interval
+        let mut overflows: u32 = 0;
+        let start = loop {
+            let capture = timer.capture(channel);
+            let wrapped = timer.clear_update_flag().is_ok();
+            if let Ok(count) = capture {
+                if wrapped && count >= 0x8000 {
+                    overflows += 1;
+                }
+                break count;
+            }
+        };
+
+        // the next falling edge, only an overflow before the capture counts
+        let end = loop {
+            let capture = timer.capture(channel);
+            let wrapped = timer.clear_update_flag().is_ok();
+            match capture {
+                Ok(count) => {
+                    if wrapped && count < 0x8000 {
+                        overflows += 1;
+                    }
+                    break count;
+                },
+                Err(_) => if wrapped {
+                    overflows += 1;
+                },
+            }
+        };
+        timer.pause();
+
+        let ticks = (overflows << 16).wrapping_add(end as u32).wrapping_sub(start as u32);
+        if ticks == 0 {
+            return Err(Error::BaudRate);
+        }
+        let measured = (timer.clock_speed(rcc) as u64 * bits / ticks as u64) as u32;
+
+        let mut rate = measured;
+        for &standard in STANDARD_BAUD_RATES.iter() {
+            let error = (measured as f32 - standard as f32) * 100.0 / standard as f32;
+            if error <= BAUD_TOLERANCE && error >= -BAUD_TOLERANCE {
+                rate = standard;
+                break;
+            }
+        }
+
+        let baud = self.set_baud_rate(rcc, rate)?;
+
+        // drop whatever was received at the wrong baud rate
+        loop {
+            if let Err(Error::WouldBlock) = self.read() {
+                break;
+            }
+        }
+
+        Ok(baud)
+    }
+
+    /// Sets word length, parity and stop bits
+    ///
+    /// Returns `Err` if the frame format isn't supported
+    fn set_format(self, config: Config) -> Result<()> {
+        let m = Self::word_length(config)?;
+        let (pce, ps) = match config.parity {
+            Parity::None => (0, 0),
+            Parity::Even => (1, 0),
+            Parity::Odd => (1, 1),
+        };
+
+        self.usart.cr1.modify(|_, w| unsafe { w.m().bits(m)
+                                                .pce().bits(pce)
+                                                .ps().bits(ps) });
+        self.usart.cr2.modify(|_, w| unsafe { w.stop().bits(config.stop_bits as u8) });
+
+        Ok(())
+    }
+
+    /// Returns the M bit for `config`
+    ///
+    /// Returns `Err` if the frame format isn't supported
+    fn word_length(config: Config) -> Result<u8> {
+        // the word length includes the parity bit
+        match (config.data_bits, config.parity) {
+            (DataBits::Eight, Parity::None) => Ok(0),
+            (DataBits::Eight, _) | (DataBits::Nine, Parity::None) => Ok(1),
+            (DataBits::Nine, _) => Err(Error::FrameFormat),
+        }
+    }
+
+    /// Changes baud rate and frame format of an initialized interface
+    ///
+    /// Blocks until the byte being sent is complete. GPIO, flow control and
+    /// interrupt settings are left alone. On error the previous settings are
+    /// kept.
+    ///
+    /// Returns the baud rate actually generated, or `Err` if it is off by more
+    /// than `BAUD_TOLERANCE` or the frame format isn't supported
+    pub fn reconfigure(self, rcc: &Rcc, config: Config) -> Result<Baud> {
+        // reject the frame format before touching any register
+        Self::word_length(config)?;
+
+        while self.usart.sr.read().tc().bits() == 0 {}
+        self.usart.cr1.modify(|_, w| unsafe { w.ue().bits(0) });
+
+        let result = self.set_baud_rate(rcc, config.baud_rate);
+        if result.is_ok() {
+            // can't fail, the format was checked above
+            self.set_format(config).ok();
+        }
+
+        self.usart.cr1.modify(|_, w| unsafe { w.ue().bits(1) });
+        result
+    }
+
+    /// Returns the clock feeding the baud rate generator
+    pub fn clock_speed(self, rcc: &Rcc) -> u32 {
+        let speeds = frequency::ClockSpeeds::get(rcc);
+
+        // USART1 sits on APB2, USART2 and USART3 on APB1
+        if &*self.usart as *const _ == USART1.get() as *const _ {
+            speeds.apb2
+        } else {
+            speeds.apb1
+        }
+    }
+
+    /// Programs the baud rate generator
+    ///
+    /// Returns the baud rate actually generated, or `Err` if `baud_rate` is 0
+    /// or the generated rate is off by more than `BAUD_TOLERANCE`
+    pub fn set_baud_rate(self, rcc: &Rcc, baud_rate: u32) -> Result<Baud> {
+        if baud_rate == 0 {
+            return Err(Error::BaudRate);
+        }
+        let pclk = self.clock_speed(rcc);
+
+        // BRR holds USARTDIV * 16 = PCLK / BAUD, round to the nearest value
+        let brr = (pclk + baud_rate / 2) / baud_rate;
+        if brr < 16 || brr > 0xFFFF {
+            return Err(Error::BaudRate);
+        }
+
+        let rate = (pclk + brr / 2) / brr;
+        let error = (rate as f32 - baud_rate as f32) * 100.0 / baud_rate as f32;
+        if error > BAUD_TOLERANCE || error < -BAUD_TOLERANCE {
+            return Err(Error::BaudRate);
+        }
+
+        let fraction = (brr & 0b1111) as u8;
+        let mantissa = (brr >> 4) as u16;
+        self.usart
+            .brr
+            .write(
+                |w| unsafe {
+                    w.div_fraction()
+                        .bits(fraction)
+                        .div_mantissa()
+                        .bits(mantissa)
+                },
+            );
+
+        Ok(Baud { rate, error })
     }
 
     /// Reads a byte from the RX buffer
     ///
-    /// Returns `None` if the buffer is empty
-    pub fn read(self) -> Option<u8> {
-        if self.usart.sr.read().rxne().bits() == 1 {
-            // NOTE(read_volatile) the register is 9 bits big but we'll only
-            // work with the first 8 bits
-            Some(
+    /// With 9 bit words the 9th bit is dropped, use `read9` instead.
+    ///
+    /// Returns `Err(WouldBlock)` if the buffer is empty, or the receive error
+    /// reported by the USART. Error flags are cleared by the read.
+    pub fn read(self) -> Result<u8> {
+        self.read9().map(|word| word as u8)
+    }
+
+    /// Reads a 9 bit word from the RX buffer, for `DataBits::Nine`
+    ///
+    /// Returns `Err(WouldBlock)` if the buffer is empty, or the receive error
+    /// reported by the USART. Error flags are cleared by the read.
+    ///
+    /// On an overrun the word still in the RX buffer is valid, it is returned
+    /// and the next call returns `Err(Overrun)`. An overrun flagged together
+    /// with a parity, framing or noise error is reported the same way, after
+    /// that error.
+    pub fn read9(self) -> Result<u16> {
+        if let Some(overrun) = self.overrun_flag() {
+            if overrun.swap(false, Ordering::Relaxed) {
+                return Err(Error::Overrun);
+            }
+        }
+
+        let sr = self.usart.sr.read();
+
+        // NOTE(read_volatile) the register is 9 bits big, the upper bits of
+        // the half word read as zero
+        // reading SR and then DR clears the PE, FE, NE and ORE flags, read DR
+        // even on error so RXNE doesn't keep the interrupt pending
+        let error = if sr.pe().bits() == 1 {
+            Error::Parity
+        } else if sr.fe().bits() == 1 {
+            Error::Framing
+        } else if sr.ne().bits() == 1 {
+            Error::Noise
+        } else if sr.rxne().bits() == 1 {
+            if sr.ore().bits() == 1 {
+                if let Some(overrun) = self.overrun_flag() {
+                    overrun.store(true, Ordering::Relaxed);
+                }
+            }
+            return Ok(
                 unsafe {
-                    ptr::read_volatile(&self.usart.dr as *const _ as *const u8)
+                    ptr::read_volatile(&self.usart.dr as *const _ as *const u16) & 0x1FF
                 },
-            )
+            );
         } else {
-            None
+            return Err(Error::WouldBlock);
+        };
+
+        // the DR read below also clears ORE, report it on the next call
+        if sr.ore().bits() == 1 {
+            if let Some(overrun) = self.overrun_flag() {
+                overrun.store(true, Ordering::Relaxed);
+            }
+        }
+        unsafe {
+            ptr::read_volatile(&self.usart.dr as *const _ as *const u16);
         }
+        Err(error)
     }
 
     /// Writes byte into the TX buffer
     ///
     /// Returns `Err` if the buffer is already full
     pub fn write(self, byte: u8) -> Result<()> {
+        self.write9(byte as u16)
+    }
+
+    /// Writes a 9 bit word into the TX buffer, for `DataBits::Nine`
+    ///
+    /// Bits above the 9th are ignored. Returns `Err` if the buffer is already
+    /// full
+    pub fn write9(self, word: u16) -> Result<()> {
         if self.usart.sr.read().txe().bits() == 1 {
             unsafe {
-                ptr::write_volatile(&self.usart.dr as *const _ as *mut u8, byte)
+                ptr::write_volatile(&self.usart.dr as *const _ as *mut u16, word & 0x1FF)
             }
             Ok(())
         } else {
-            Err(Error { _0: () })
+            Err(Error::WouldBlock)
+        }
+    }
+
+    /// Enables the interrupt for `event`
+    pub fn listen(self, event: Event) {
+        match event {
+            Event::Rxne => self.usart.cr1.modify(|_, w| unsafe { w.rxneie().bits(1) }),
+            Event::Txe => self.usart.cr1.modify(|_, w| unsafe { w.txeie().bits(1) }),
+            Event::Tc => self.usart.cr1.modify(|_, w| unsafe { w.tcie().bits(1) }),
+            Event::Idle => self.usart.cr1.modify(|_, w| unsafe { w.idleie().bits(1) }),
+            Event::LinBreak => self.usart.cr2.modify(|_, w| unsafe { w.lbdie().bits(1) }),
         }
     }
-}
\ No newline at end of file
+
+    /// Disables the interrupt for `event`
+    pub fn unlisten(self, event: Event) {
+        match event {
+            Event::Rxne => self.usart.cr1.modify(|_, w| unsafe { w.rxneie().bits(0) }),
+            Event::Txe => self.usart.cr1.modify(|_, w| unsafe { w.txeie().bits(0) }),
+            Event::Tc => self.usart.cr1.modify(|_, w| unsafe { w.tcie().bits(0) }),
+            Event::Idle => self.usart.cr1.modify(|_, w| unsafe { w.idleie().bits(0) }),
+            Event::LinBreak => self.usart.cr2.modify(|_, w| unsafe { w.lbdie().bits(0) }),
+        }
+    }
+
+    /// Returns `true` if the status flag of `event` is set
+    pub fn is_set(self, event: Event) -> bool {
+        let sr = self.usart.sr.read();
+        match event {
+            Event::Rxne => sr.rxne().bits() == 1,
+            Event::Txe => sr.txe().bits() == 1,
+            Event::Tc => sr.tc().bits() == 1,
+            Event::Idle => sr.idle().bits() == 1,
+            Event::LinBreak => sr.lbd().bits() == 1,
+        }
+    }
+
+    /// Switches the USART to LIN mode
+    ///
+    /// LIN frames are always 8N1, the word length, parity, stop bits, clock
+    /// output, smartcard, half-duplex and IrDA settings are cleared
+    pub fn enable_lin(self, length: BreakLength) {
+        self.usart.cr1.modify(|_, w| unsafe { w.ue().bits(0) });
+
+        // m = 8 data bits
+        // pce = no parity
+        self.usart.cr1.modify(|_, w| unsafe { w.m().bits(0)
+                                                .pce().bits(0)
+                                                .ps().bits(0) });
+
+        self.usart.cr2.modify(|_, w| unsafe { w.stop().bits(0b00)
+                                                .clken().bits(0)
+                                                .lbdl().bits(length as u8)
+                                                .linen().bits(1) });
+        self.usart.cr3.modify(|_, w| unsafe { w.scen().bits(0)
+                                                .hdsel().bits(0)
+                                                .iren().bits(0) });
+
+        self.usart.cr1.modify(|_, w| unsafe { w.ue().bits(1) });
+    }
+
+    /// Sends a break character after the byte currently being transmitted
+    pub fn send_break(self) {
+        // SBK is cleared by hardware during the stop bit of the break
+        self.usart.cr1.modify(|_, w| unsafe { w.sbk().bits(1) });
+    }
+
+    /// Clears the LIN break detection flag
+    ///
+    /// Returns `Err` if no break has been detected
+    pub fn clear_break_flag(self) -> Result<()> {
+        if self.usart.sr.read().lbd().bits() == 1 {
+            // NOTE rc_w0 flags, writing one leaves the other flags untouched
+            self.usart.sr.write(|w| unsafe { w.bits(!(1 << 8)) });
+            Ok(())
+        } else {
+            Err(Error::WouldBlock)
+        }
+    }
+
+    /// Clears the idle line flag
+    ///
+    /// IDLE is cleared by reading SR and then DR. DR is only read here when
+    /// the RX buffer is empty, as it is with `read_dma`. Otherwise the pending
+    /// byte is left for `read`, which clears IDLE when it reads DR.
+    ///
+    /// Returns `Err` if the line hasn't gone idle
+    pub fn clear_idle_flag(self) -> Result<()> {
+        let sr = self.usart.sr.read();
+        if sr.idle().bits() == 1 {
+            if sr.rxne().bits() == 0 {
+                // NOTE(safe) the RX buffer is empty, no byte is lost
+                unsafe {
+                    ptr::read_volatile(&self.usart.dr as *const _ as *const u8);
+                }
+            }
+            Ok(())
+        } else {
+            Err(Error::WouldBlock)
+        }
+    }
+
+    /// Returns the pending overrun flag of this USART
+    fn overrun_flag(self) -> Option<&'static AtomicBool> {
+        match &*self.usart as *const _{
+            x if x == USART1.get() as *const _ => Some(&OVERRUN[0]),
+            x if x == USART2.get() as *const _ => Some(&OVERRUN[1]),
+            x if x == USART3.get() as *const _ => Some(&OVERRUN[2]),
+            _ => None,
+        }
+    }
+
+    /// Returns the DMA1 channels serving this USART as `(tx, rx)`
+    pub fn dma_channels(self) -> Option<(u8, u8)> {
+        match &*self.usart as *const _{
+            x if x == USART1.get() as *const _ => Some((4, 5)),
+            x if x == USART2.get() as *const _ => Some((7, 6)),
+            x if x == USART3.get() as *const _ => Some((2, 3)),
+            _ => None,
+        }
+    }
+
+    /// Transmits `buffer` using DMA
+    ///
+    /// The transfer is done when the DMA channel reports `TransferComplete`,
+    /// wait for the USART `Tc` flag before disabling the transmitter.
+    ///
+    /// Returns `Err` if `dma` isn't the TX channel of this USART or `buffer`
+    /// is longer than 65535 bytes
+    pub fn write_dma(self, dma: &dma::Channel, buffer: &'static [u8]) -> Result<()> {
+        match self.dma_channels() {
+            Some((tx, _)) if tx == dma.number() => {},
+            _ => return Err(Error::DmaChannel),
+        }
+        if buffer.len() > u16::MAX as usize {
+            return Err(Error::BufferLength);
+        }
+
+        self.usart.cr3.modify(|_, w| unsafe { w.dmat().bits(1) });
+        // clear TC so it signals the end of this transfer
+        // NOTE rc_w0 flags, writing one leaves the other flags untouched
+        self.usart.sr.write(|w| unsafe { w.bits(!(1 << 6)) });
+
+        // NOTE(safe) the channel serves this USART's TX requests and the
+        // buffer is static
+        unsafe {
+            dma.start(&dma::Transfer {
+                peripheral: &self.usart.dr as *const _ as u32,
+                memory: buffer.as_ptr() as u32,
+                len: buffer.len() as u16,
+                direction: dma::Direction::MemoryToPeripheral,
+                size: dma::Size::Byte,
+                circular: false,
+            });
+        }
+        Ok(())
+    }
+
+    /// Receives into `buffer` using DMA
+    ///
+    /// The RXNE interrupt is disabled since DMA drains the RX buffer. With
+    /// `circular` the buffer is refilled forever, listen to the DMA
+    /// `HalfTransfer` and `TransferComplete` events and read each half with
+    /// `RxTransfer::first_half` and `RxTransfer::second_half`. Listen to
+    /// `Event::Idle` to end variable length frames, `RxTransfer::received`
+    /// returns what arrived so far.
+    ///
+    /// Returns `Err` if `dma` isn't the RX channel of this USART or `buffer`
+    /// is longer than 65535 bytes
+    pub fn read_dma(self, dma: &dma::Channel, buffer: &'static mut [u8], circular: bool) -> Result<RxTransfer> {
+        match self.dma_channels() {
+            Some((_, rx)) if rx == dma.number() => {},
+            _ => return Err(Error::DmaChannel),
+        }
+        if buffer.len() > u16::MAX as usize {
+            return Err(Error::BufferLength);
+        }
+
+        self.unlisten(Event::Rxne);
+        self.usart.cr3.modify(|_, w| unsafe { w.dmar().bits(1) });
+
+        // NOTE(safe) the channel serves this USART's RX requests and the
+        // returned transfer owns the buffer until it is stopped
+        unsafe {
+            dma.start(&dma::Transfer {
+                peripheral: &self.usart.dr as *const _ as u32,
+                memory: buffer.as_mut_ptr() as u32,
+                len: buffer.len() as u16,
+                direction: dma::Direction::PeripheralToMemory,
+                size: dma::Size::Byte,
+                circular,
+            });
+        }
+        Ok(RxTransfer { buffer })
+    }
+}
+
+/// DMA reception started by `Serial::read_dma`, owns the buffer until
+/// `stop` hands it back
+///
+/// Every method takes the DMA channel the transfer was started on
+pub struct RxTransfer {
+    buffer: &'static mut [u8],
+}
+
+impl RxTransfer {
+    /// Returns the number of bytes written into the buffer so far
+    ///
+    /// In circular mode this is the position in the current lap
+    pub fn received(&self, dma: &dma::Channel) -> usize {
+        self.buffer.len() - dma.remaining() as usize
+    }
+
+    /// Returns the bytes received so far, for a one shot transfer
+    ///
+    /// The DMA only writes past these bytes, so they stay valid until the
+    /// transfer is stopped
+    pub fn data(&self, dma: &dma::Channel) -> &[u8] {
+        &self.buffer[..self.received(dma)]
+    }
+
+    /// Returns the first `len / 2` bytes of the buffer
+    ///
+    /// Read it after `HalfTransfer`, before a circular transfer wraps around
+    /// and overwrites it
+    pub fn first_half(&self) -> &[u8] {
+        &self.buffer[..self.buffer.len() / 2]
+    }
+
+    /// Returns the rest of the buffer
+    ///
+    /// Read it after `TransferComplete`, before a circular transfer reaches
+    /// `HalfTransfer` again
+    pub fn second_half(&self) -> &[u8] {
+        &self.buffer[self.buffer.len() / 2..]
+    }
+
+    /// Stops the transfer and returns the buffer, with the number of bytes
+    /// received into it
+    ///
+    /// Stop at an idle line and call `Serial::read_dma` again to receive the
+    /// next frame from the start of the buffer
+    pub fn stop(self, dma: &dma::Channel) -> (&'static mut [u8], usize) {
+        dma.stop();
+        let received = self.received(dma);
+        (self.buffer, received)
+    }
+}
+
+impl<'a> fmt::Write for Serial<'a> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for &byte in s.as_bytes() {
+            // block until the TX buffer is free
+            while self.write(byte).is_err() {}
+        }
+        Ok(())
+    }
+}
+
+/// Formatted output through a `BufferedSerial`, returned by
+/// `BufferedSerial::writer`
+///
+/// Never blocks, output that doesn't fit in the TX ring buffer is dropped and
+/// reported as `fmt::Error`
+pub struct Writer<'a, 'b> {
+    buffered: &'b mut BufferedSerial,
+    serial: Serial<'a>,
+}
+
+impl<'a, 'b> fmt::Write for Writer<'a, 'b> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let bytes = s.as_bytes();
+        if self.buffered.write_all(self.serial, bytes) == bytes.len() {
+            Ok(())
+        } else {
+            Err(fmt::Error)
+        }
+    }
+}
+
+/// Buffered driver statistics
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stats {
+    /// bytes dropped because the RX ring buffer was full
+    pub rx_overflow: u32,
+    /// bytes dropped because the TX ring buffer was full
+    pub tx_overflow: u32,
+    /// receive errors reported by the USART
+    pub rx_errors: u32,
+}
+
+/// Interrupt driven serial driver with RX and TX ring buffers
+///
+/// Meant to be stored in a static resource, call `on_interrupt` from the
+/// USART interrupt. Neither side ever blocks, bytes that don't fit in a ring
+/// buffer are dropped and counted in `Stats`.
+pub struct BufferedSerial {
+    rx: RingBuffer<u8, [u8; RX_BUFFER_SIZE]>,
+    tx: RingBuffer<u8, [u8; TX_BUFFER_SIZE]>,
+    stats: Stats,
+    xon_xoff: bool,
+    // the remote end sent XOFF
+    paused: bool,
+    // we sent XOFF
+    xoff_sent: bool,
+    // XON or XOFF waiting to jump the TX queue
+    control: Option<u8>,
+    // bytes left to send before the requested break
+    until_break: Option<usize>,
+}
+
+impl BufferedSerial {
+    /// returns an empty driver
+    pub const fn new() -> BufferedSerial {
+        BufferedSerial {
+            rx: RingBuffer::new([0; RX_BUFFER_SIZE]),
+            tx: RingBuffer::new([0; TX_BUFFER_SIZE]),
+            stats: Stats { rx_overflow: 0, tx_overflow: 0, rx_errors: 0 },
+            xon_xoff: false,
+            paused: false,
+            xoff_sent: false,
+            control: None,
+            until_break: None,
+        }
+    }
+
+    /// Enables or disables XON/XOFF software flow control
+    ///
+    /// When enabled, received XOFF pauses transmission until XON arrives, and
+    /// XOFF/XON are sent when the RX ring buffer crosses `XOFF_WATERMARK` and
+    /// `XON_WATERMARK`. XON and XOFF are never stored in the RX ring buffer.
+    pub fn set_xon_xoff(&mut self, serial: Serial, enabled: bool) {
+        self.xon_xoff = enabled;
+        if !enabled {
+            self.paused = false;
+            self.xoff_sent = false;
+            self.control = None;
+            serial.listen(Event::Txe);
+        }
+    }
+
+    /// Sends a break once the bytes already queued have been sent
+    pub fn send_break(&mut self, serial: Serial) {
+        self.until_break = Some(self.tx.len());
+        serial.listen(Event::Txe);
+    }
+
+    /// Services the USART, call this from its interrupt
+    ///
+    /// Moves received bytes into the RX ring buffer and feeds the TX buffer
+    /// from the TX ring buffer. The TXE interrupt is disabled once the TX ring
+    /// buffer is empty, and TC is used to signal the end of the transmission.
+    pub fn on_interrupt(&mut self, serial: Serial) {
+        loop {
+            match serial.read() {
+                Ok(XOFF) if self.xon_xoff => self.paused = true,
+                Ok(XON) if self.xon_xoff => {
+                    self.paused = false;
+                    serial.listen(Event::Txe);
+                },
+                Ok(byte) => if self.rx.enqueue(byte).is_err() {
+                    self.stats.rx_overflow += 1;
+                },
+                Err(Error::WouldBlock) => break,
+                Err(_) => self.stats.rx_errors += 1,
+            }
+        }
+
+        if self.xon_xoff && !self.xoff_sent && self.rx.len() >= XOFF_WATERMARK {
+            self.control = Some(XOFF);
+            self.xoff_sent = true;
+            serial.listen(Event::Txe);
+        }
+
+        while serial.is_set(Event::Txe) {
+            // flow control bytes go out even while paused
+            if let Some(control) = self.control.take() {
+                serial.write(control).ok();
+                continue;
+            }
+
+            if self.until_break == Some(0) {
+                serial.send_break();
+                self.until_break = None;
+            }
+
+            if self.paused {
+                serial.unlisten(Event::Txe);
+                break;
+            }
+
+            match self.tx.dequeue() {
+                Some(byte) => {
+                    // can't fail, TXE is set
+                    serial.write(byte).ok();
+                    if let Some(ref mut n) = self.until_break {
+                        *n -= 1;
+                    }
+                },
+                None => {
+                    serial.unlisten(Event::Txe);
+                    break;
+                },
+            }
+        }
+
+        if self.tx.is_empty() && serial.is_set(Event::Tc) {
+            serial.unlisten(Event::Tc);
+        }
+    }
+
+    /// Returns the next received byte, if any
+    ///
+    /// Sends XON when XON/XOFF is enabled and the RX ring buffer has drained
+    /// below `XON_WATERMARK`
+    pub fn read(&mut self, serial: Serial) -> Option<u8> {
+        let byte = self.rx.dequeue();
+
+        if self.xoff_sent && self.rx.len() <= XON_WATERMARK {
+            self.control = Some(XON);
+            self.xoff_sent = false;
+            serial.listen(Event::Txe);
+        }
+
+        byte
+    }
+
+    /// Queues `bytes` for transmission without blocking
+    ///
+    /// Returns the number of bytes queued, the rest are dropped
+    pub fn write_all(&mut self, serial: Serial, bytes: &[u8]) -> usize {
+        let mut queued = 0;
+        for &byte in bytes {
+            if self.tx.enqueue(byte).is_err() {
+                self.stats.tx_overflow += (bytes.len() - queued) as u32;
+                break;
+            }
+            queued += 1;
+        }
+
+        if queued != 0 {
+            serial.listen(Event::Txe);
+            serial.listen(Event::Tc);
+        }
+        queued
+    }
+
+    /// Returns the number of bytes that can be queued without dropping any
+    pub fn room(&self) -> usize {
+        self.tx.capacity() - self.tx.len()
+    }
+
+    /// Returns a `fmt::Write` handle that queues its output
+    pub fn writer<'a, 'b>(&'b mut self, serial: Serial<'a>) -> Writer<'a, 'b> {
+        Writer { buffered: self, serial }
+    }
+
+    /// Returns `true` when every queued byte has left the shift register
+    pub fn is_flushed(&self, serial: Serial) -> bool {
+        self.tx.is_empty() && self.control.is_none() && self.until_break.is_none() &&
+            serial.is_set(Event::Tc)
+    }
+
+    /// Returns the driver statistics
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+}
+
+/// Prints to a serial handle, a `Serial` or a `&mut serial::Writer`
+#[macro_export]
+macro_rules! sprint {
+    ($serial:expr, $($arg:tt)*) => {{
+        use ::core::fmt::Write;
+        let mut out = $serial;
+        out.write_fmt(format_args!($($arg)*)).ok();
+    }};
+}
+
+/// Prints to a serial handle, with a newline
+#[macro_export]
+macro_rules! sprintln {
+    ($serial:expr) => {
+        sprint!($serial, "\r\n")
+    };
+    ($serial:expr, $fmt:expr) => {
+        sprint!($serial, concat!($fmt, "\r\n"))
+    };
+    ($serial:expr, $fmt:expr, $($arg:tt)*) => {
+        sprint!($serial, concat!($fmt, "\r\n"), $($arg)*)
+    };
+}
@@ -0,0 +1,54 @@
+//! Command shell
+//!
+//! Runs the line editor of `bluepill_protocol::shell` on a buffered serial
+//! driver, see that module for the editing keys and the command table.
+//!
+//! `poll` paces the output so it never overflows the TX ring buffer: a
+//! received byte is only handled once there is room for `RESPONSE_SIZE`
+//! bytes, and `help` is queued one line at a time as the ring drains.
+//!
+//! ``` ignore
+//! // USART interrupt
+//! buffered.on_interrupt(serial);
+//! shell::poll(&mut shell, &mut buffered, serial);
+//! ```
+
+use core::cmp;
+
+use serial::{BufferedSerial, Serial};
+
+pub use bluepill_protocol::shell::{Command, Shell, HISTORY_SIZE, LINE_SIZE, MAX_ARGS, PROMPT};
+
+/// Most output a received byte can cause, without `help`
+///
+/// Redrawing a recalled line takes 70 bytes, the output of a command handler
+/// has to fit in 90 bytes. `serial::TX_BUFFER_SIZE` is larger than this.
+pub const RESPONSE_SIZE: usize = 96;
+
+/// Feeds the bytes received by a buffered driver to `shell`, output is
+/// queued on the same driver
+///
+/// Call this from the USART interrupt after `BufferedSerial::on_interrupt`.
+/// Bytes are left in the RX ring buffer while the TX ring buffer is too
+/// full, the TXE interrupts that drain it call `poll` again.
+pub fn poll(shell: &mut Shell, buffered: &mut BufferedSerial, serial: Serial) {
+    loop {
+        if let Some(len) = shell.help_len() {
+            // don't wait for more room than a response needs, a longer line
+            // is cut short
+            if buffered.room() < cmp::min(len, RESPONSE_SIZE) {
+                return;
+            }
+            shell.help_line(&mut buffered.writer(serial));
+            continue;
+        }
+
+        if buffered.room() < RESPONSE_SIZE {
+            return;
+        }
+        match buffered.read(serial) {
+            Some(byte) => shell.input(byte, &mut buffered.writer(serial)),
+            None => return,
+        }
+    }
+}
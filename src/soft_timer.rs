@@ -0,0 +1,180 @@
+//! Software timers
+//!
+//! Multiplexes many virtual timers on a single hardware `timer::Timer`. The
+//! hardware timer is configured for periodic update events as usual and
+//! `TimerWheel::tick` is called from its interrupt, all virtual timer delays
+//! are counted in those ticks.
+
+use cortex_m::peripheral::Nvic;
+use stm32f103xx::Interrupt;
+
+/// Maximum number of virtual timers
+pub const MAX_TIMERS: usize = 8;
+
+/// Specialized `Result` type
+pub type Result<T> = ::core::result::Result<T, Error>;
+
+/// An error
+#[derive(Debug)]
+pub enum Error {
+    /// all the timer slots are in use
+    Full,
+    /// the timer id doesn't refer to a scheduled timer
+    InvalidTimer,
+    /// a periodic timer needs a delay of at least one tick
+    InvalidDelay,
+}
+
+/// Handle to a scheduled virtual timer
+///
+/// Stays invalid once the timer expired or was cancelled, even after its slot
+/// is reused by another timer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TimerId {
+    slot: usize,
+    generation: u32,
+}
+
+/// What happens when a virtual timer expires
+#[derive(Clone, Copy)]
+pub enum Action {
+    /// call a function from the hardware timer interrupt
+    Callback(fn()),
+    /// pend an interrupt, for example the one bound to an RTFM task
+    Pend(Interrupt),
+}
+
+/// Virtual timer mode
+#[derive(Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// fire once and then free the slot
+    OneShot,
+    /// fire every `delay` ticks until cancelled
+    Periodic,
+}
+
+#[derive(Clone, Copy)]
+struct VirtualTimer {
+    deadline: u32,
+    delay: u32,
+    mode: Mode,
+    action: Action,
+}
+
+/// Software timer service
+pub struct TimerWheel {
+    now: u32,
+    timers: [Option<VirtualTimer>; MAX_TIMERS],
+    // bumped every time a slot is scheduled, so stale handles don't match
+    generations: [u32; MAX_TIMERS],
+}
+
+impl TimerWheel {
+    /// returns a timer service with no timers scheduled
+    pub const fn new() -> TimerWheel {
+        TimerWheel { now: 0, timers: [None; MAX_TIMERS], generations: [0; MAX_TIMERS] }
+    }
+
+    /// Returns the number of ticks elapsed since the service was created
+    pub fn now(&self) -> u32 {
+        self.now
+    }
+
+    /// Schedules a virtual timer to expire after `delay` ticks
+    ///
+    /// Returns `Err` if there are no free slots, or if a `Periodic` timer has
+    /// a `delay` of 0
+    pub fn schedule(&mut self, delay: u32, mode: Mode, action: Action) -> Result<TimerId> {
+        if mode == Mode::Periodic && delay == 0 {
+            return Err(Error::InvalidDelay);
+        }
+        let now = self.now;
+        match self.timers.iter().position(|t| t.is_none()) {
+            Some(i) => {
+                self.timers[i] = Some(VirtualTimer {
+                    deadline: now.wrapping_add(delay),
+                    delay,
+                    mode,
+                    action,
+                });
+                self.generations[i] = self.generations[i].wrapping_add(1);
+                Ok(TimerId { slot: i, generation: self.generations[i] })
+            }
+            None => Err(Error::Full),
+        }
+    }
+
+    /// Cancels a virtual timer and frees its slot
+    ///
+    /// Returns `Err` if the timer already expired or was cancelled
+    pub fn cancel(&mut self, id: TimerId) -> Result<()> {
+        let slot = self.slot(id)?;
+        self.timers[slot] = None;
+        Ok(())
+    }
+
+    /// Restarts a virtual timer with a new `delay`, keeping its mode and action
+    ///
+    /// Returns `Err` if the timer already expired or was cancelled, or if a
+    /// `Periodic` timer gets a `delay` of 0
+    pub fn rearm(&mut self, id: TimerId, delay: u32) -> Result<()> {
+        let now = self.now;
+        let slot = self.slot(id)?;
+        match self.timers[slot] {
+            Some(ref mut timer) => {
+                if timer.mode == Mode::Periodic && delay == 0 {
+                    return Err(Error::InvalidDelay);
+                }
+                timer.delay = delay;
+                timer.deadline = now.wrapping_add(delay);
+                Ok(())
+            }
+            None => Err(Error::InvalidTimer),
+        }
+    }
+
+    /// Returns `true` if the timer is still scheduled
+    pub fn is_scheduled(&self, id: TimerId) -> bool {
+        self.slot(id).is_ok()
+    }
+
+    /// Returns the slot of a scheduled timer
+    fn slot(&self, id: TimerId) -> Result<usize> {
+        if self.timers[id.slot].is_some() && self.generations[id.slot] == id.generation {
+            Ok(id.slot)
+        } else {
+            Err(Error::InvalidTimer)
+        }
+    }
+
+    /// Advances the service by one tick, firing every expired timer
+    ///
+    /// Call this from the hardware timer interrupt after clearing its update
+    /// flag
+    pub fn tick(&mut self, nvic: &Nvic) {
+        self.now = self.now.wrapping_add(1);
+        let now = self.now;
+
+        for slot in self.timers.iter_mut() {
+            let timer = match *slot {
+                // compare with wrapping arithmetic so the tick counter can roll over
+                Some(timer) if now.wrapping_sub(timer.deadline) as i32 >= 0 => timer,
+                _ => continue,
+            };
+
+            if timer.mode == Mode::Periodic {
+                *slot = Some(VirtualTimer {
+                    deadline: timer.deadline.wrapping_add(timer.delay),
+                    ..timer
+                });
+            } else {
+                *slot = None;
+            }
+
+            match timer.action {
+                Action::Callback(f) => f(),
+                Action::Pend(interrupt) => nvic.set_pending(interrupt),
+            }
+        }
+    }
+}
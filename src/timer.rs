@@ -1,8 +1,18 @@
 //! Periodic timer
+//!
+//! # Master/slave chaining
+//!
+//! TIM1 - TIM4 can be linked through their internal trigger (ITR) lines, a
+//! master timer drives TRGO and a slave timer uses it as reset, gate, start
+//! trigger or clock. This is used to build 32 bit counters out of two timers
+//! and to start several PWM timers in phase.
 use core::u16;
 
 use cast::{u16, u32};
-use stm32f103xx::{Rcc, tim2, TIM2, TIM3, TIM4, TIM5};
+use stm32f103xx::{Rcc, rcc, tim2, TIM1, TIM2, TIM3, TIM4, TIM5};
+
+use dma;
+use frequency;
 
 pub use hal::timer::Timer as halTimer;
 
@@ -14,6 +24,138 @@ pub struct Error {
     _0: (),
 }
 
+impl Error {
+    pub(crate) fn new() -> Error {
+        Error { _0: () }
+    }
+}
+
+/// Master mode, what the timer drives on TRGO
+#[derive(Clone, Copy)]
+pub enum MasterMode {
+    /// UG bit from EGR
+    Reset = 0b000,
+    /// counter enable
+    Enable = 0b001,
+    /// update event
+    Update = 0b010,
+    /// capture/compare pulse
+    ComparePulse = 0b011,
+    /// OC1REF
+    Compare1 = 0b100,
+    /// OC2REF
+    Compare2 = 0b101,
+    /// OC3REF
+    Compare3 = 0b110,
+    /// OC4REF
+    Compare4 = 0b111,
+}
+
+/// Slave mode, what the timer does with its trigger input
+#[derive(Clone, Copy)]
+pub enum SlaveMode {
+    /// slave mode disabled, counter clocked by the internal clock
+    Disabled = 0b000,
+    /// rising edge of the trigger reinitializes the counter
+    Reset = 0b100,
+    /// counter runs while the trigger is high
+    Gated = 0b101,
+    /// rising edge of the trigger starts the counter
+    Trigger = 0b110,
+    /// rising edges of the trigger clock the counter
+    ExternalClock1 = 0b111,
+}
+
+/// Trigger input selection
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Trigger {
+    /// internal trigger 0
+    Itr0 = 0b000,
+    /// internal trigger 1
+    Itr1 = 0b001,
+    /// internal trigger 2
+    Itr2 = 0b010,
+    /// internal trigger 3
+    Itr3 = 0b011,
+    /// TI1 edge detector
+    Ti1Edge = 0b100,
+    /// filtered timer input 1
+    Ti1 = 0b101,
+    /// filtered timer input 2
+    Ti2 = 0b110,
+    /// external trigger input
+    Etr = 0b111,
+}
+
+/// External count input
+#[derive(Clone, Copy)]
+pub enum CountInput {
+    /// external trigger pin (ETR), external clock mode 2
+    Etr,
+    /// rising edges on timer input 1
+    Ti1,
+    /// rising edges on timer input 2
+    Ti2,
+}
+
+/// Capture/compare channel
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Channel {
+    /// channel 1
+    C1,
+    /// channel 2
+    C2,
+    /// channel 3
+    C3,
+    /// channel 4
+    C4,
+}
+
+/// Input capture edge
+#[derive(Clone, Copy)]
+pub enum Edge {
+    /// capture on rising edges
+    Rising = 0,
+    /// capture on falling edges
+    Falling = 1,
+}
+
+/// Output compare mode, what the channel output does when CNT matches CCRx
+#[derive(Clone, Copy)]
+pub enum OutputCompare {
+    /// output is not affected
+    Frozen = 0b000,
+    /// output goes high on match
+    ActiveOnMatch = 0b001,
+    /// output goes low on match
+    InactiveOnMatch = 0b010,
+    /// output toggles on match
+    Toggle = 0b011,
+    /// output forced low
+    ForceInactive = 0b100,
+    /// output forced high
+    ForceActive = 0b101,
+    /// high while CNT < CCRx
+    Pwm1 = 0b110,
+    /// low while CNT < CCRx
+    Pwm2 = 0b111,
+}
+
+/// Registers written by a DMA burst on every update event
+#[derive(Clone, Copy)]
+pub enum Burst {
+    /// the compare register of a channel, one item per update event
+    Compare(Channel),
+    /// `len` consecutive registers starting at register index `base`, for
+    /// example base 11 (ARR) and len 3 writes ARR, RCR and CCR1
+    Registers {
+        /// DMA base address, in 32 bit words from the start of the timer
+        base: u8,
+        /// number of registers per burst
+        len: u8,
+    },
+}
+
 /// General purpose timer
 pub struct Timer<'a>{
     /// general purpose timer
@@ -28,23 +170,417 @@ impl<'a> Timer<'a>{
 
     /// initialize timer to frequency
     pub fn init(&self, rcc: &Rcc, ticks: u32) {
-        // Power up peripherals
+        self.power_up(rcc);
+
+        let psc = u16((ticks - 1) / u32(u16::MAX)).unwrap();
+        self.timer.psc.write(|w| w.psc().bits(psc));
+        let arr = u16(ticks / u32(psc + 1)).unwrap();
+        self.timer.arr.write(|w| w.arr().bits(arr));
+
+        // modify instead of write so master/slave and PWM settings survive
+        self.timer.dier.modify(|_, w| unsafe { w.uie().bits(1) });
+        self.timer.cr1.modify(|_, w| w.opm().continuous());
+    }
+
+    /// Powers up the timer peripheral
+    pub fn power_up(&self, rcc: &Rcc) {
         // check which memory block this timer is pointing to
         match &*self.timer as *const _{
+            x if x == TIM1.get() as *const _ => rcc.apb2enr.modify(|_, w| w.tim1en().enabled()),
             x if x == TIM2.get() as *const _ => rcc.apb1enr.modify(|_, w| w.tim2en().enabled()),
             x if x == TIM3.get() as *const _ => rcc.apb1enr.modify(|_, w| w.tim3en().enabled()),
             x if x == TIM4.get() as *const _ => rcc.apb1enr.modify(|_, w| w.tim4en().enabled()),
             x if x == TIM5.get() as *const _ => rcc.apb1enr.modify(|_, w| w.tim5en().enabled()),
             _ => {},
         }
+    }
 
-        let psc = u16((ticks - 1) / u32(u16::MAX)).unwrap();
-        self.timer.psc.write(|w| w.psc().bits(psc));
-        let arr = u16(ticks / u32(psc + 1)).unwrap();
-        self.timer.arr.write(|w| w.arr().bits(arr));
+    /// Returns the frequency of the clock feeding the timer counter
+    pub fn clock_speed(&self, rcc: &Rcc) -> u32 {
+        let speeds = frequency::ClockSpeeds::get(rcc);
 
-        self.timer.dier.write(|w| unsafe { w.uie().bits(1) });
-        self.timer.cr1.write(|w| w.opm().continuous());
+        // if the APB prescaler is anything other than 1 the timer clock is multiplied by 2
+        if self.number() == Some(1) {
+            let mult = if rcc.cfgr.read().ppre2() == rcc::cfgr::Ppre2R::Div1 { 1 } else { 2 };
+            speeds.apb2 * mult
+        } else {
+            let mult = if rcc.cfgr.read().ppre1() == rcc::cfgr::Ppre1R::Div1 { 1 } else { 2 };
+            speeds.apb1 * mult
+        }
+    }
+
+    /// Configures the counter to count edges on an external input instead of
+    /// the internal clock
+    ///
+    /// The counter runs free over the full 16 bit range, the input pin has to
+    /// be configured as an input separately
+    pub fn count_external(&self, rcc: &Rcc, input: CountInput) {
+        self.power_up(rcc);
+        self.pause();
+
+        self.timer.psc.write(|w| w.psc().bits(0));
+        self.timer.arr.write(|w| w.arr().bits(u16::MAX));
+
+        match input {
+            CountInput::Etr => {
+                self.set_slave_mode(SlaveMode::Disabled, Trigger::Etr);
+                // ece = external clock mode 2
+                // etp = rising edge
+                // etps = no prescaler
+                // etf = no filter
+                self.timer.smcr.modify(|_, w| unsafe { w.ece().bits(1)
+                                                         .etp().bits(0)
+                                                         .etps().bits(0)
+                                                         .etf().bits(0) });
+            },
+            CountInput::Ti1 => {
+                // cc1s = input mapped on TI1
+                // cc1p = rising edge
+                self.timer.ccmr1_input.modify(|_, w| unsafe { w.cc1s().bits(0b01) });
+                self.timer.ccer.modify(|_, w| unsafe { w.cc1p().bits(0) });
+                self.set_slave_mode(SlaveMode::ExternalClock1, Trigger::Ti1);
+            },
+            CountInput::Ti2 => {
+                // cc2s = input mapped on TI2
+                // cc2p = rising edge
+                self.timer.ccmr1_input.modify(|_, w| unsafe { w.cc2s().bits(0b01) });
+                self.timer.ccer.modify(|_, w| unsafe { w.cc2p().bits(0) });
+                self.set_slave_mode(SlaveMode::ExternalClock1, Trigger::Ti2);
+            },
+        }
+
+        self.timer.egr.write(|w| unsafe { w.ug().bits(1) });
+        self.resume();
+    }
+
+    /// Sets the output compare mode of `channel` and enables its output
+    ///
+    /// Preload is only enabled for the PWM modes, in the other modes a new
+    /// compare value takes effect immediately so compare events can be chained
+    /// from the compare interrupt
+    pub fn set_output_compare(&self, channel: Channel, mode: OutputCompare) {
+        let preload = match mode {
+            OutputCompare::Pwm1 | OutputCompare::Pwm2 => 1,
+            _ => 0,
+        };
+
+        // ocXm = compare mode
+        // ocXpe = preload enable
+        // ccXe = output enable
+        // ccXp = active high
+        match channel {
+            Channel::C1 => { self.timer.ccmr1_output.modify(|_,w| unsafe{ w.oc1m().bits(mode as u8)
+                                                                           .oc1pe().bits(preload) });
+                             self.timer.ccer.modify(|_, w| unsafe{ w.cc1e().bits(1)
+                                                                     .cc1p().bits(0) }); },
+            Channel::C2 => { self.timer.ccmr1_output.modify(|_,w| unsafe{ w.oc2m().bits(mode as u8)
+                                                                           .oc2pe().bits(preload) });
+                             self.timer.ccer.modify(|_, w| unsafe{ w.cc2e().bits(1)
+                                                                     .cc2p().bits(0) }); },
+            Channel::C3 => { self.timer.ccmr2_output.modify(|_,w| unsafe{ w.oc3m().bits(mode as u8)
+                                                                           .oc3pe().bits(preload) });
+                             self.timer.ccer.modify(|_, w| unsafe{ w.cc3e().bits(1)
+                                                                     .cc3p().bits(0) }); },
+            Channel::C4 => { self.timer.ccmr2_output.modify(|_,w| unsafe{ w.oc4m().bits(mode as u8)
+                                                                           .oc4pe().bits(preload) });
+                             self.timer.ccer.modify(|_, w| unsafe{ w.cc4e().bits(1)
+                                                                     .cc4p().bits(0) }); },
+        }
+
+        // TIM1 is an advanced control timer, its outputs stay disconnected
+        // from the pins until the main output enable bit is set
+        if self.number() == Some(1) {
+            // NOTE(safe) atomic read-modify-write of a register only this
+            // timer uses
+            unsafe { (*TIM1.get()).bdtr.modify(|_, w| w.moe().bits(1)) }
+        }
+    }
+
+    /// Sets the compare value of `channel`
+    pub fn set_compare(&self, channel: Channel, value: u16) {
+        match channel {
+            Channel::C1 => self.timer.ccr1.write(|w| unsafe{ w.ccr1().bits(value) }),
+            Channel::C2 => self.timer.ccr2.write(|w| unsafe{ w.ccr2().bits(value) }),
+            Channel::C3 => self.timer.ccr3.write(|w| unsafe{ w.ccr3().bits(value) }),
+            Channel::C4 => self.timer.ccr4.write(|w| unsafe{ w.ccr4().bits(value) }),
+        }
+    }
+
+    /// Returns the compare value of `channel`
+    pub fn compare(&self, channel: Channel) -> u16 {
+        match channel {
+            Channel::C1 => self.timer.ccr1.read().ccr1().bits(),
+            Channel::C2 => self.timer.ccr2.read().ccr2().bits(),
+            Channel::C3 => self.timer.ccr3.read().ccr3().bits(),
+            Channel::C4 => self.timer.ccr4.read().ccr4().bits(),
+        }
+    }
+
+    /// Returns the current counter value
+    pub fn count(&self) -> u16 {
+        (self.timer.cnt.read().bits() & 0xFFFF) as u16
+    }
+
+    /// Configures `channel` to capture the counter on `edge` of its own
+    /// timer input, TIx
+    pub fn set_input_capture(&self, channel: Channel, edge: Edge) {
+        // ccXs = input mapped on TIx
+        // ccXp = capture polarity
+        // ccXe = capture enable
+        match channel {
+            Channel::C1 => { self.timer.ccmr1_input.modify(|_,w| unsafe{ w.cc1s().bits(0b01) });
+                             self.timer.ccer.modify(|_, w| unsafe{ w.cc1p().bits(edge as u8)
+                                                                     .cc1e().bits(1) }); },
+            Channel::C2 => { self.timer.ccmr1_input.modify(|_,w| unsafe{ w.cc2s().bits(0b01) });
+                             self.timer.ccer.modify(|_, w| unsafe{ w.cc2p().bits(edge as u8)
+                                                                     .cc2e().bits(1) }); },
+            Channel::C3 => { self.timer.ccmr2_input.modify(|_,w| unsafe{ w.cc3s().bits(0b01) });
+                             self.timer.ccer.modify(|_, w| unsafe{ w.cc3p().bits(edge as u8)
+                                                                     .cc3e().bits(1) }); },
+            Channel::C4 => { self.timer.ccmr2_input.modify(|_,w| unsafe{ w.cc4s().bits(0b01) });
+                             self.timer.ccer.modify(|_, w| unsafe{ w.cc4p().bits(edge as u8)
+                                                                     .cc4e().bits(1) }); },
+        }
+    }
+
+    /// Returns the captured counter value of `channel`, reading it clears the
+    /// capture flag
+    ///
+    /// Returns `Err` if nothing has been captured
+    pub fn capture(&self, channel: Channel) -> Result<u16> {
+        if self.timer.sr.read().bits() & Self::compare_flag(channel) == 0 {
+            Err(Error::new())
+        } else {
+            Ok(self.compare(channel))
+        }
+    }
+
+    /// Enables the capture/compare interrupt of `channel`
+    pub fn listen_compare(&self, channel: Channel) {
+        self.set_compare_interrupt(channel, 1);
+    }
+
+    /// Disables the capture/compare interrupt of `channel`
+    pub fn unlisten_compare(&self, channel: Channel) {
+        self.set_compare_interrupt(channel, 0);
+    }
+
+    /// Clears the capture/compare flag of `channel`
+    ///
+    /// Returns `Err` if no compare event has occurred
+    pub fn clear_compare_flag(&self, channel: Channel) -> Result<()> {
+        let mask = Self::compare_flag(channel);
+        if self.timer.sr.read().bits() & mask == 0 {
+            Err(Error::new())
+        } else {
+            // NOTE rc_w0 flags, writing one leaves the other flags untouched
+            self.timer.sr.write(|w| unsafe { w.bits(!mask) });
+            Ok(())
+        }
+    }
+
+    fn set_compare_interrupt(&self, channel: Channel, bit: u8) {
+        match channel {
+            Channel::C1 => self.timer.dier.modify(|_, w| unsafe { w.cc1ie().bits(bit) }),
+            Channel::C2 => self.timer.dier.modify(|_, w| unsafe { w.cc2ie().bits(bit) }),
+            Channel::C3 => self.timer.dier.modify(|_, w| unsafe { w.cc3ie().bits(bit) }),
+            Channel::C4 => self.timer.dier.modify(|_, w| unsafe { w.cc4ie().bits(bit) }),
+        }
+    }
+
+    /// Returns the CCxIF bit of `channel` in the status register
+    fn compare_flag(channel: Channel) -> u32 {
+        match channel {
+            Channel::C1 => 1 << 1,
+            Channel::C2 => 1 << 2,
+            Channel::C3 => 1 << 3,
+            Channel::C4 => 1 << 4,
+        }
+    }
+
+    /// Returns the DMA1 channel that serves the update event of this timer
+    pub fn update_dma_channel(&self) -> Option<u8> {
+        match self.number() {
+            Some(1) => Some(5),
+            Some(2) => Some(2),
+            Some(3) => Some(3),
+            Some(4) => Some(7),
+            _ => None,
+        }
+    }
+
+    /// Streams `buffer` into the timer registers selected by `burst`, one
+    /// burst per update event
+    ///
+    /// `dma` must be the channel returned by `update_dma_channel`, the timer
+    /// period has to be set up already (for example by `Pin::init`).
+    ///
+    /// Returns `Err` if `dma` doesn't serve this timer, `buffer` holds more
+    /// than 65535 items or the burst registers are out of range
+    pub fn stream(&self, dma: &dma::Channel, buffer: &'static [u16], burst: Burst, circular: bool) -> Result<()> {
+        if self.update_dma_channel() != Some(dma.number()) || buffer.len() > u16::MAX as usize {
+            return Err(Error::new());
+        }
+
+        let (base, len) = match burst {
+            // CCR1 is at offset 0x34
+            Burst::Compare(Channel::C1) => (13, 1),
+            Burst::Compare(Channel::C2) => (14, 1),
+            Burst::Compare(Channel::C3) => (15, 1),
+            Burst::Compare(Channel::C4) => (16, 1),
+            Burst::Registers { base, len } => (base, len),
+        };
+
+        // DBA is 5 bits wide and a burst is 1 to 18 transfers
+        if base > 31 || len == 0 || len > 18 {
+            return Err(Error::new());
+        }
+
+        // dba = first register of the burst
+        // dbl = burst length - 1
+        self.timer.dcr.write(|w| unsafe { w.dba().bits(base)
+                                            .dbl().bits(len - 1) });
+
+        // NOTE(safe) the channel serves this timer's update requests and the
+        // buffer is static
+        unsafe {
+            dma.start(&dma::Transfer {
+                peripheral: &self.timer.dmar as *const _ as u32,
+                memory: buffer.as_ptr() as u32,
+                len: buffer.len() as u16,
+                direction: dma::Direction::MemoryToPeripheral,
+                size: dma::Size::HalfWord,
+                circular,
+            });
+        }
+
+        // request a DMA transfer on every update event
+        self.timer.dier.modify(|_, w| unsafe { w.ude().bits(1) });
+        Ok(())
+    }
+
+    /// Stops a stream started with `stream`
+    pub fn stop_stream(&self, dma: &dma::Channel) {
+        self.timer.dier.modify(|_, w| unsafe { w.ude().bits(0) });
+        dma.stop();
+    }
+
+    /// Selects what the timer outputs on TRGO
+    pub fn set_master_mode(&self, mode: MasterMode) {
+        self.timer.cr2.modify(|_, w| unsafe { w.mms().bits(mode as u8) });
+    }
+
+    /// Configures the slave mode controller
+    pub fn set_slave_mode(&self, mode: SlaveMode, trigger: Trigger) {
+        // TS must only be changed while the slave mode controller is disabled
+        self.timer.smcr.modify(|_, w| unsafe { w.sms().bits(SlaveMode::Disabled as u8) });
+        self.timer.smcr.modify(|_, w| unsafe { w.ts().bits(trigger as u8) });
+        self.timer.smcr.modify(|_, w| unsafe { w.sms().bits(mode as u8) });
+    }
+
+    /// Returns the internal trigger this timer uses to listen to `master`
+    ///
+    /// Returns `None` if there is no ITR connection between the two timers
+    pub fn internal_trigger(&self, master: &Timer) -> Option<Trigger> {
+        // ITR0 - ITR3 connections for each slave, from the reference manual
+        let itr = match self.number() {
+            Some(1) => [5, 2, 3, 4],
+            Some(2) => [1, 8, 3, 4],
+            Some(3) => [1, 2, 5, 4],
+            Some(4) => [1, 2, 3, 8],
+            Some(5) => [2, 3, 4, 8],
+            _ => return None,
+        };
+
+        match master.number() {
+            Some(m) if m == itr[0] => Some(Trigger::Itr0),
+            Some(m) if m == itr[1] => Some(Trigger::Itr1),
+            Some(m) if m == itr[2] => Some(Trigger::Itr2),
+            Some(m) if m == itr[3] => Some(Trigger::Itr3),
+            _ => None,
+        }
+    }
+
+    /// Chains `high` to this timer to form a 32 bit counter
+    ///
+    /// This timer counts the low half and clocks `high` on every overflow.
+    /// Both timers must already be initialized, use `read_cascaded` to read
+    /// the combined count.
+    ///
+    /// Returns `Err` if the timers can't be chained
+    pub fn cascade(&self, high: &Timer) -> Result<()> {
+        let trigger = match high.internal_trigger(self) {
+            Some(trigger) => trigger,
+            None => return Err(Error::new()),
+        };
+
+        self.pause();
+        high.pause();
+
+        self.timer.arr.write(|w| w.arr().bits(u16::MAX));
+        high.timer.arr.write(|w| w.arr().bits(u16::MAX));
+        high.timer.psc.write(|w| w.psc().bits(0));
+        high.timer.egr.write(|w| unsafe { w.ug().bits(1) });
+
+        self.set_master_mode(MasterMode::Update);
+        high.set_slave_mode(SlaveMode::ExternalClock1, trigger);
+
+        // the slave counts trigger edges, it has to be enabled to do so
+        high.resume();
+        Ok(())
+    }
+
+    /// Reads the 32 bit count of two timers chained with `cascade`
+    pub fn read_cascaded(&self, high: &Timer) -> u32 {
+        // re-read if the low half overflowed between the two reads
+        loop {
+            let hi = high.timer.cnt.read().bits() & 0xFFFF;
+            let lo = self.timer.cnt.read().bits() & 0xFFFF;
+            if hi == high.timer.cnt.read().bits() & 0xFFFF {
+                return (hi << 16) | lo;
+            }
+        }
+    }
+
+    /// Starts this timer and every timer in `slaves` at the same time
+    ///
+    /// Counters are reset so PWM outputs on all the timers are phase aligned.
+    ///
+    /// Returns `Err` if one of the slaves has no ITR connection to this timer
+    pub fn start_synchronized(&self, slaves: &[&Timer]) -> Result<()> {
+        for slave in slaves {
+            if slave.internal_trigger(self).is_none() {
+                return Err(Error::new());
+            }
+        }
+
+        self.pause();
+        self.timer.cnt.write(|w| unsafe { w.bits(0) });
+
+        for slave in slaves {
+            slave.pause();
+            slave.timer.cnt.write(|w| unsafe { w.bits(0) });
+            if let Some(trigger) = slave.internal_trigger(self) {
+                slave.set_slave_mode(SlaveMode::Trigger, trigger);
+            }
+        }
+
+        // slaves set CEN when the master drives its counter enable on TRGO
+        self.set_master_mode(MasterMode::Enable);
+        self.resume();
+        Ok(())
+    }
+
+    /// Returns the timer number, 1 for TIM1 and so on
+    fn number(&self) -> Option<u8> {
+        match &*self.timer as *const _{
+            x if x == TIM1.get() as *const _ => Some(1),
+            x if x == TIM2.get() as *const _ => Some(2),
+            x if x == TIM3.get() as *const _ => Some(3),
+            x if x == TIM4.get() as *const _ => Some(4),
+            x if x == TIM5.get() as *const _ => Some(5),
+            _ => None,
+        }
     }
 
     /// Clears the update event flag
@@ -52,7 +588,7 @@ impl<'a> Timer<'a>{
     /// Returns `Err` if no update event has occurred
     pub fn clear_update_flag(&self) -> Result<()> {
         if self.timer.sr.read().uif().is_no_update() {
-            Err(Error { _0: () })
+            Err(Error::new())
         } else {
             self.timer.sr.modify(|_, w| w.uif().clear());
             Ok(())